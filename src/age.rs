@@ -0,0 +1,75 @@
+use chrono::Duration;
+
+/// Parses ages like `90s`, `30m`, `12h`, `7d` or `2w`.
+pub fn parse_age(unparsed: &str) -> Result<Duration, String> {
+    let unparsed = unparsed.trim();
+    let unit_idx = unparsed
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("age '{}' is missing a unit (s, m, h, d, w)", unparsed))?;
+    let (amount, unit) = unparsed.split_at(unit_idx);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("age '{}' does not start with a number", unparsed))?;
+
+    let age = match unit {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        u => return Err(format!("unknown unit '{}' in age '{}'", u, unparsed)),
+    };
+    age.ok_or_else(|| format!("age '{}' out of range", unparsed))
+}
+
+/// Formats an age with its largest whole unit, e.g. `3d` or `5h`.
+pub fn format_age(age: Duration) -> String {
+    if age.num_days() != 0 {
+        format!("{}d", age.num_days())
+    } else if age.num_hours() != 0 {
+        format!("{}h", age.num_hours())
+    } else if age.num_minutes() != 0 {
+        format!("{}m", age.num_minutes())
+    } else {
+        format!("{}s", age.num_seconds())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_age("90s").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_age("30m").unwrap(), Duration::minutes(30));
+        assert_eq!(parse_age("12h").unwrap(), Duration::hours(12));
+        assert_eq!(parse_age("7d").unwrap(), Duration::days(7));
+        assert_eq!(parse_age("2w").unwrap(), Duration::weeks(2));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_age("7").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("7y").is_err());
+        assert!(parse_age("").is_err());
+    }
+
+    #[test]
+    fn parse_out_of_range() {
+        let err_msg = parse_age("99999999999999d").unwrap_err();
+        assert!(err_msg.contains("out of range"), "{}", err_msg);
+        assert!(parse_age("99999999999999w").is_err());
+        assert!(parse_age("99999999999999999999s").is_err());
+        assert!(parse_age("106751991167d").is_ok());
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_age(Duration::days(7)), "7d");
+        assert_eq!(format_age(Duration::hours(49)), "2d");
+        assert_eq!(format_age(Duration::minutes(61)), "1h");
+        assert_eq!(format_age(Duration::seconds(5)), "5s");
+    }
+}
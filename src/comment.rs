@@ -0,0 +1,111 @@
+use std::path::Path;
+
+/// How a line comment is written in a type of file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommentSyntax {
+    pub start: &'static str,
+    /// Only set for block comments like `<!-- -->`.
+    pub end: &'static str,
+}
+
+const HASH: CommentSyntax = CommentSyntax {
+    start: "#",
+    end: "",
+};
+const SLASHES: CommentSyntax = CommentSyntax {
+    start: "//",
+    end: "",
+};
+const DASHES: CommentSyntax = CommentSyntax {
+    start: "--",
+    end: "",
+};
+const XML: CommentSyntax = CommentSyntax {
+    start: "<!--",
+    end: "-->",
+};
+const SEMICOLON: CommentSyntax = CommentSyntax {
+    start: ";",
+    end: "",
+};
+
+impl CommentSyntax {
+    /// Picks the comment syntax by file extension, `#` if unknown.
+    pub fn for_filename(filename: &str) -> Self {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "js" | "jsx" | "ts" | "tsx" | "go" | "rs" | "java" | "kt" | "kts" | "scala"
+            | "swift" | "c" | "h" | "cpp" | "hpp" | "cs" | "php" | "gradle" | "groovy"
+            | "jsonc" | "json5" => SLASHES,
+            "sql" | "lua" | "hs" | "elm" => DASHES,
+            "xml" | "html" | "htm" | "md" | "pom" | "csproj" | "svg" => XML,
+            "ini" | "asm" | "s" | "clj" | "el" => SEMICOLON,
+            _ => HASH,
+        }
+    }
+
+    /// Whether a tag starting after `line_prefix` is inside a comment: the
+    /// prefix has to contain the comment marker outside of quotes, either at
+    /// the start or after whitespace.
+    pub fn is_comment_prefix(&self, line_prefix: &str) -> bool {
+        let mut quote = None;
+        let mut previous = None;
+        for (idx, c) in line_prefix.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None => {
+                    let is_word_start = previous.is_none_or(char::is_whitespace);
+                    if is_word_start && line_prefix[idx..].starts_with(self.start) {
+                        return true;
+                    }
+                }
+            }
+            previous = Some(c);
+        }
+        false
+    }
+
+    pub fn format(&self, text: &str) -> String {
+        match self.end {
+            "" => format!("{} {}", self.start, text),
+            end => format!("{} {} {}", self.start, text, end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn for_filename() {
+        assert_eq!(CommentSyntax::for_filename("Dockerfile"), HASH);
+        assert_eq!(CommentSyntax::for_filename("deploy/compose.yml"), HASH);
+        assert_eq!(CommentSyntax::for_filename("main.go"), SLASHES);
+        assert_eq!(CommentSyntax::for_filename("pom.XML"), XML);
+    }
+
+    #[test]
+    fn comment_prefix() {
+        assert!(HASH.is_comment_prefix("  # "));
+        assert!(HASH.is_comment_prefix("#pinned: "));
+        assert!(HASH.is_comment_prefix("FROM testapp # "));
+        assert!(!HASH.is_comment_prefix("FOO=\""));
+        assert!(!HASH.is_comment_prefix("FOO=\"# "));
+        assert!(!HASH.is_comment_prefix("FOO=bar#"));
+        assert!(!HASH.is_comment_prefix("// "));
+        assert!(XML.is_comment_prefix("<!-- "));
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(HASH.format("[demver]"), "# [demver]");
+        assert_eq!(XML.format("[demver]"), "<!-- [demver] -->");
+    }
+}
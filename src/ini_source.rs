@@ -1,6 +1,10 @@
+use crate::scheme;
 use crate::source;
+use crate::syntax;
 use crate::version;
 use ini::Ini;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 
 pub struct FileSource {
     filename: String,
@@ -12,74 +16,163 @@ impl FileSource {
     }
 }
 
-pub struct IniSource {
+/// An ini file, read on first use. The versions of a section are parsed once
+/// per format, and shared by all sources reading the file.
+pub struct IniFile {
     file_source: FileSource,
+    loaded: OnceLock<Result<Ini, String>>,
+    versions: Mutex<VersionsBySection>,
+}
+
+/// Each entry is filled once, outside of the lock on the map, so parsing one
+/// section doesn't block readers of the others.
+type VersionsBySection = HashMap<
+    (Option<String>, scheme::VersionFormat),
+    Arc<OnceLock<Result<Vec<version::FixedVersion>, String>>>,
+>;
+
+impl IniFile {
+    pub fn new(filename: &str) -> IniFile {
+        IniFile {
+            file_source: FileSource::new(filename.to_owned()),
+            loaded: OnceLock::new(),
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn load(&self) -> Result<&Ini, String> {
+        self.loaded
+            .get_or_init(|| {
+                Ini::load_from_file(&self.file_source.filename).map_err(|e| {
+                    format!(
+                        "Failed to open ini file {}: {}",
+                        &self.file_source.filename, e
+                    )
+                })
+            })
+            .as_ref()
+            .map_err(String::clone)
+    }
+
+    fn load_section(&self, section: Option<&str>) -> Result<&ini::Properties, String> {
+        match self.load()?.section(section) {
+            Some(c) => Ok(c),
+            None => Err("Ini file does not contain a section for the given identifier".to_owned()),
+        }
+    }
+
+    fn get_versions(
+        &self,
+        section: Option<&str>,
+        format: &scheme::VersionFormat,
+    ) -> Result<Vec<version::FixedVersion>, String> {
+        let key = (section.map(String::from), format.clone());
+        let versions = self
+            .versions
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone();
+        versions
+            .get_or_init(|| parse_versions(self.load_section(section)?, format))
+            .clone()
+    }
+}
+
+fn parse_versions(
+    section: &ini::Properties,
+    format: &scheme::VersionFormat,
+) -> Result<Vec<version::FixedVersion>, String> {
+    let mut versions: Vec<version::FixedVersion> = vec![];
+
+    for (key, value) in section.iter() {
+        let parsed_version = match format.parse_version(key) {
+            Ok(v) => v,
+            Err(_) => {
+                // println!("unable to parse '{}', skipping", key);
+                continue;
+            }
+        };
+
+        // a value may carry the release date: `<hash> @ <timestamp>`
+        let (hash, released) = match value.split_once(" @ ") {
+            Some((hash, timestamp)) => {
+                let released = syntax::parse_timestamp(timestamp.trim())
+                    .map_err(|e| format!("invalid release date for version {}: {}", key, e))?;
+                (hash.trim(), Some(released))
+            }
+            None => (value, None),
+        };
+
+        versions.push(version::FixedVersion {
+            raw_version: key.to_string(),
+            version: parsed_version,
+            hash: String::from(hash),
+            released,
+        });
+    }
+
+    Ok(versions)
+}
+
+pub struct IniSource {
+    file: Arc<IniFile>,
+    section: Option<String>,
 }
 
 impl IniSource {
+    /// Reads a file of its own, outside of the session.
+    #[cfg(test)]
     pub fn new(filename: &str) -> IniSource {
+        Self::from_file(Arc::new(IniFile::new(filename)))
+    }
+
+    /// Reads a file that may be shared with other sources.
+    pub fn from_file(file: Arc<IniFile>) -> IniSource {
         IniSource {
-            file_source: FileSource {
-                filename: filename.to_owned(),
-            },
+            file,
+            section: None,
         }
     }
+
+    /// Reads all versions from this section instead of the one named after
+    /// the identifier.
+    pub fn with_section(mut self, section: Option<String>) -> Self {
+        self.section = section;
+        self
+    }
+
+    fn get_section<'a>(&'a self, identifier: Option<&'a str>) -> Option<&'a str> {
+        self.section.as_deref().or(identifier)
+    }
 }
 
 impl source::VersionSource for IniSource {
-    fn get_fixed_version(
+    fn get_versions(
         &self,
-        requested_version: &semver::VersionReq,
         identifier: Option<&str>,
-    ) -> source::FixedVersionResult {
-        let conf = match Ini::load_from_file(&self.file_source.filename) {
-            Ok(c) => c,
-            Err(e) => {
-                return source::FixedVersionResult::Err(format!(
-                    "Failed to open ini file {}: {}",
-                    &self.file_source.filename, e
-                ))
-            }
-        };
-        let section = match conf.section(identifier) {
-            Some(c) => c,
-            None => {
-                return source::FixedVersionResult::Err(
-                    "Ini file does not contain a section for the given identifier".to_owned(),
-                )
-            }
-        };
+        format: &scheme::VersionFormat,
+    ) -> Result<Vec<version::FixedVersion>, String> {
+        self.file.get_versions(self.get_section(identifier), format)
+    }
 
-        let mut best_match: Option<version::FixedVersion> = None;
-
-        for (key, value) in section.iter() {
-            let semver = match semver::Version::parse(key) {
-                Ok(s) => s,
-                Err(_) => {
-                    // println!("unable to parse '{}' as semver, skipping", key);
-                    continue;
-                }
-            };
-
-            if requested_version.matches(&semver) {
-                // println!("Version {} matches Request {}", semver, requested_version);
-
-                best_match = if best_match.is_some() && best_match.as_ref().unwrap().semver > semver
-                {
-                    best_match
-                } else {
-                    Some(version::FixedVersion {
-                        raw_version: key.to_string(),
-                        semver: semver,
-                        hash: String::from(value),
-                    })
-                }
-            }
-        }
+    fn get_identifiers(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .file
+            .load()?
+            .sections()
+            .flatten()
+            .map(String::from)
+            .collect())
+    }
 
-        match best_match {
-            Some(m) => Ok(m),
-            None => Err("no match found".to_owned()),
+    /// Channels are keys starting with `@`, e.g. `@stable=1.5.0`.
+    fn get_channel(&self, identifier: Option<&str>, channel: &str) -> Result<String, String> {
+        let section = self.file.load_section(self.get_section(identifier))?;
+        match section.get(format!("@{}", channel)) {
+            Some(raw_version) => Ok(raw_version.trim().to_owned()),
+            None => Err(format!("Ini file does not define the channel @{}", channel)),
         }
     }
 }
@@ -87,6 +180,7 @@ impl source::VersionSource for IniSource {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::ResolvePolicy;
     use crate::source::VersionSource;
 
     #[test]
@@ -94,9 +188,13 @@ mod tests {
         let filename = String::from("./test/simple/versions.ini");
         let ini = IniSource::new(&filename);
 
-        let req = semver::VersionReq::parse("~1.0.0").unwrap();
+        let req = scheme::VersionScheme::Semver.parse_req("~1.0.0").unwrap();
 
-        let ver = ini.get_fixed_version(&req, Some("testapp"));
+        let ver = ini.get_fixed_version(
+            &req,
+            Some("testapp"),
+            &ResolvePolicy::new(chrono::Utc::now()),
+        );
         let ver = ver.unwrap();
 
         assert_eq!(ver.get_hash(), "1")
@@ -107,11 +205,166 @@ mod tests {
         let filename = String::from("./test/simple/versions.ini");
         let ini = IniSource::new(&filename);
 
-        let req = semver::VersionReq::parse("^1.0.0").unwrap();
+        let req = scheme::VersionScheme::Semver.parse_req("^1.0.0").unwrap();
 
-        let ver = ini.get_fixed_version(&req, Some("testapp"));
+        let ver = ini.get_fixed_version(
+            &req,
+            Some("testapp"),
+            &ResolvePolicy::new(chrono::Utc::now()),
+        );
         let ver = ver.unwrap();
 
         assert_eq!(ver.get_hash(), "15")
     }
+
+    #[test]
+    fn test_section() {
+        let ini = IniSource::new("./test/simple/versions.ini").with_section(Some("testapp".into()));
+
+        let req = scheme::VersionScheme::Semver.parse_req("~1.0.0").unwrap();
+
+        let ver = ini.get_fixed_version(
+            &req,
+            Some("other-name"),
+            &ResolvePolicy::new(chrono::Utc::now()),
+        );
+        assert!(ver.is_ok());
+    }
+
+    #[test]
+    fn test_shared_file() {
+        let file = Arc::new(IniFile::new("./test/groups/versions.ini"));
+        let format = scheme::VersionFormat::default();
+        let api = IniSource::from_file(file.clone());
+        let worker = IniSource::from_file(file.clone()).with_section(Some("app-worker".into()));
+
+        assert_eq!(api.get_versions(Some("app-api"), &format).unwrap().len(), 3);
+        assert_eq!(api.get_versions(Some("app-api"), &format).unwrap().len(), 3);
+        assert_eq!(
+            worker.get_versions(Some("other"), &format).unwrap().len(),
+            2
+        );
+        assert_eq!(file.versions.lock().unwrap().len(), 2);
+
+        // another format of the same section is parsed separately
+        let loose = scheme::VersionFormat {
+            scheme: scheme::VersionScheme::Loose,
+            variant: None,
+        };
+        assert_eq!(api.get_versions(Some("app-api"), &loose).unwrap().len(), 3);
+        assert_eq!(file.versions.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_identifiers() {
+        let ini = IniSource::new("./test/groups/versions.ini");
+
+        assert_eq!(ini.get_identifiers().unwrap(), ["app-api", "app-worker"]);
+    }
+
+    #[test]
+    fn test_release_dates() {
+        let filename = String::from("./test/cooldown/versions.ini");
+        let ini = IniSource::new(&filename);
+
+        let req = scheme::VersionScheme::Semver.parse_req("^1.0.0").unwrap();
+        let now = syntax::parse_timestamp("2020-12-05T00-00-00").unwrap();
+
+        let ver = ini.get_fixed_version(&req, Some("testapp"), &ResolvePolicy::new(now));
+        assert_eq!(ver.unwrap().get_hash(), "sha256:16");
+
+        let policy = ResolvePolicy::new(now).with_min_age(Some(chrono::Duration::days(3)));
+        let ver = ini.get_fixed_version(&req, Some("testapp"), &policy);
+        assert_eq!(ver.unwrap().get_hash(), "sha256:15");
+
+        let policy = ResolvePolicy::new(now).with_min_age(Some(chrono::Duration::days(60)));
+        let ver = ini.get_fixed_version(&req, Some("testapp"), &policy);
+        assert!(ver.is_err());
+    }
+
+    #[test]
+    fn test_dotted_scheme() {
+        let filename = String::from("./test/schemes/versions.ini");
+        let ini = IniSource::new(&filename);
+        let policy = ResolvePolicy::new(chrono::Utc::now());
+
+        let req = scheme::VersionScheme::Dotted.parse_req("^1.2").unwrap();
+        let ver = ini.get_fixed_version(&req, Some("fourpart"), &policy);
+        assert_eq!(ver.unwrap().raw_version, "1.2.3.10");
+
+        let req = scheme::VersionScheme::Calver.parse_req("2024.05").unwrap();
+        let ver = ini.get_fixed_version(&req, Some("calver"), &policy);
+        assert_eq!(ver.unwrap().raw_version, "2024.05.14");
+
+        let req = scheme::VersionScheme::Loose.parse_req("^3").unwrap();
+        let ver = ini.get_fixed_version(&req, Some("loose"), &policy);
+        assert_eq!(ver.unwrap().raw_version, "v3.12");
+    }
+
+    #[test]
+    fn test_variants() {
+        let filename = String::from("./test/variants/versions.ini");
+        let ini = IniSource::new(&filename);
+        let policy = ResolvePolicy::new(chrono::Utc::now());
+        let format = |scheme, variant: Option<&str>| scheme::VersionFormat {
+            scheme,
+            variant: variant.map(String::from),
+        };
+
+        let req = format(scheme::VersionScheme::Dotted, Some("alpine"))
+            .parse_req("^1.21")
+            .unwrap();
+        let ver = ini.get_fixed_version(&req, Some("golang"), &policy);
+        assert_eq!(ver.unwrap().raw_version, "1.22-alpine");
+
+        let req = format(scheme::VersionScheme::Dotted, None)
+            .parse_req("^1.21")
+            .unwrap();
+        let ver = ini.get_fixed_version(&req, Some("golang"), &policy);
+        assert_eq!(ver.unwrap().raw_version, "1.23");
+
+        let req = format(scheme::VersionScheme::Semver, Some("alpine"))
+            .parse_req("^20")
+            .unwrap();
+        let ver = ini.get_fixed_version(&req, Some("node"), &policy);
+        assert_eq!(ver.unwrap().raw_version, "20.2.0-alpine");
+    }
+
+    #[test]
+    fn test_prerelease_without_variant() {
+        let ini = IniSource::new("./test/variants/versions.ini");
+        let req = scheme::VersionScheme::Semver.parse_req("^1.21.2").unwrap();
+        let policy = |prerelease| {
+            ResolvePolicy::new(chrono::Utc::now()).with_prerelease(Some(
+                crate::policy::PrereleasePolicy::parse(prerelease).unwrap(),
+            ))
+        };
+
+        let ver = ini.get_fixed_version(&req, Some("mixed"), &policy("alpine"));
+        assert_eq!(ver.unwrap().raw_version, "1.21.2");
+
+        let ver = ini.get_fixed_version(&req, Some("mixed"), &policy("rc"));
+        assert_eq!(ver.unwrap().raw_version, "1.21.3-rc1");
+    }
+
+    #[test]
+    fn test_channels() {
+        let filename = String::from("./test/channels/versions.ini");
+        let ini = IniSource::new(&filename);
+        let policy = ResolvePolicy::new(chrono::Utc::now());
+
+        let req = scheme::VersionScheme::Semver.parse_req("@stable").unwrap();
+        let ver = ini.get_fixed_version(&req, Some("testapp"), &policy);
+        assert_eq!(ver.unwrap().get_hash(), "sha256:15");
+
+        let req = scheme::VersionScheme::Semver.parse_req("@broken").unwrap();
+        assert!(ini
+            .get_fixed_version(&req, Some("testapp"), &policy)
+            .is_err());
+
+        let req = scheme::VersionScheme::Semver.parse_req("@nightly").unwrap();
+        assert!(ini
+            .get_fixed_version(&req, Some("testapp"), &policy)
+            .is_err());
+    }
 }
@@ -0,0 +1,356 @@
+use crate::policy::ResolvePolicy;
+use crate::syntax;
+use crate::tag_adder::{self, NewTag};
+use crate::target::{self, TargetKind, TargetValue};
+use crate::text_file::TextFile;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// An image reference without a digest, e.g. `FROM testapp:1.0`.
+#[derive(Debug, PartialEq)]
+pub struct UnpinnedImage {
+    /// Starting at 1.
+    pub line_nr: usize,
+    pub repository: String,
+    pub tag: Option<String>,
+}
+
+/// A tag proposed for an unpinned image.
+#[derive(Debug)]
+pub struct Proposal {
+    pub image: UnpinnedImage,
+    pub new_tag: NewTag,
+}
+
+#[derive(Debug, Default)]
+pub struct FileProposals {
+    pub proposals: Vec<Proposal>,
+    /// Images that could not be resolved, with the reason.
+    pub failures: Vec<(UnpinnedImage, String)>,
+}
+
+/// Collects Dockerfiles, compose files and manifests. Files given directly
+/// are always taken, directories are searched recursively.
+pub fn find_candidate_files(paths: &[String]) -> Result<Vec<String>, String> {
+    let mut files = vec![];
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            walk_dir(path, &mut files)?;
+        } else {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(files)
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<String>) -> Result<(), String> {
+    let read_err = |e: std::io::Error| format!("failed to read {}: {}", dir.display(), e);
+    let mut entries = fs::read_dir(dir)
+        .map_err(read_err)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(read_err)?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            walk_dir(&path, files)?;
+        } else if is_candidate_file(&name) {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+fn is_candidate_file(name: &str) -> bool {
+    name.starts_with("Dockerfile")
+        || name.ends_with(".dockerfile")
+        || name.ends_with(".yml")
+        || name.ends_with(".yaml")
+}
+
+/// Finds image references that are not pinned by digest. Variables, `scratch`,
+/// build stages and lines already following a demver tag are skipped.
+pub fn find_unpinned_images(content: &str) -> Vec<UnpinnedImage> {
+    let mut images = vec![];
+    let mut stages: Vec<String> = vec![];
+    let mut previous_line = "";
+
+    for (idx, line) in content.lines().enumerate() {
+        let target = TargetValue::find(line).filter(|t| t.kind == TargetKind::Image);
+        if let Some(target) = target {
+            let image = &line[target.start..target.end];
+            let is_stage = stages.iter().any(|s| s == image);
+            if !image.contains('@')
+                && !image.contains('$')
+                && image != "scratch"
+                && !is_stage
+                && !previous_line.contains("[demver")
+            {
+                let repository = target::get_repository(image);
+                let tag = image.get(repository.len() + 1..).map(String::from);
+                images.push(UnpinnedImage {
+                    line_nr: idx + 1,
+                    repository: repository.to_owned(),
+                    tag,
+                });
+            }
+            if let Some(stage) = get_stage_name(line) {
+                stages.push(stage);
+            }
+        }
+        previous_line = line;
+    }
+
+    images
+}
+
+/// `FROM image AS stage` makes `stage` usable as an image later on.
+fn get_stage_name(line: &str) -> Option<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [.., as_keyword, stage] if as_keyword.eq_ignore_ascii_case("as") => Some(stage.to_string()),
+        _ => None,
+    }
+}
+
+/// Requirements to try for an image tag, best first: a channel of the same
+/// name, then the release line of a version like `1.21` or `1.21-alpine`.
+/// Versions that aren't full semver, as docker tags often are, use a scheme
+/// that understands them.
+fn get_candidate_reqs(tag: &Option<String>) -> Vec<(String, String)> {
+    let tag = tag.as_deref().unwrap_or("latest");
+    let mut candidates = vec![(format!("@{}", tag), String::new())];
+
+    let (version, variant) = match tag.split_once('-') {
+        Some((version, variant)) => (version, Some(variant)),
+        None => (tag, None),
+    };
+    let version = version.trim_start_matches(['v', 'V']);
+    let nr_parts = version.split('.').count();
+    if !version.is_empty() && version.split('.').all(|p| p.parse::<u64>().is_ok()) {
+        let mut attributes = vec![];
+        match nr_parts {
+            3 => {}
+            1 | 2 => attributes.push("scheme=loose".to_owned()),
+            _ => attributes.push("scheme=dotted".to_owned()),
+        }
+        if let Some(variant) = variant {
+            attributes.push(format!("variant={}", variant));
+        }
+        candidates.push((format!("~{}", version), attributes.join(",")));
+    } else if tag == "latest" {
+        candidates.push(("*".to_owned(), String::new()));
+    }
+    candidates
+}
+
+/// Proposes a tag for every unpinned image in the file that can be resolved
+/// in the source.
+pub fn propose_tags(
+    filename: &str,
+    source_filename: &str,
+    policy: &ResolvePolicy,
+) -> Result<FileProposals, String> {
+    let text_file = TextFile::read(filename)?;
+    let version_source = format!(
+        "file({})",
+        syntax::format_argument(&get_relative_path(filename, source_filename)?.to_string_lossy())
+    );
+
+    let mut file_proposals = FileProposals::default();
+    for image in find_unpinned_images(&text_file.content) {
+        let mut err_msg = String::new();
+        let mut new_tag_found = None;
+        for (version_req, attributes) in get_candidate_reqs(&image.tag) {
+            let new_tag = NewTag {
+                version_req,
+                version_source: version_source.clone(),
+                identifier: image.repository.clone(),
+                attributes,
+            };
+            match tag_adder::add_tag_to_content(
+                filename,
+                &text_file,
+                image.line_nr,
+                &new_tag,
+                policy,
+            ) {
+                Ok(_) => {
+                    new_tag_found = Some(new_tag);
+                    break;
+                }
+                Err(e) => err_msg = e,
+            }
+        }
+        match new_tag_found {
+            Some(new_tag) => file_proposals.proposals.push(Proposal { image, new_tag }),
+            None => file_proposals.failures.push((image, err_msg)),
+        }
+    }
+
+    Ok(file_proposals)
+}
+
+/// Combines accepted proposals of one file. Each proposal was computed on
+/// the original content, so later ones are shifted by the lines added above.
+pub fn apply_proposals(
+    filename: &str,
+    proposals: &[&Proposal],
+    policy: &ResolvePolicy,
+) -> Result<TextFile, String> {
+    let mut text_file = TextFile::read(filename)?;
+    for (nr_added, proposal) in proposals.iter().enumerate() {
+        let updated = tag_adder::add_tag_to_content(
+            filename,
+            &text_file,
+            proposal.image.line_nr + nr_added,
+            &proposal.new_tag,
+            policy,
+        )?;
+        text_file = text_file.with_content(updated);
+    }
+    Ok(text_file)
+}
+
+/// File sources in tags are relative to the file containing the tag.
+fn get_relative_path(filename: &str, source_filename: &str) -> Result<PathBuf, String> {
+    let canonicalize = |path: &Path| {
+        fs::canonicalize(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))
+    };
+    let source = canonicalize(Path::new(source_filename))?;
+    let file = canonicalize(Path::new(filename))?;
+    let dir = file.parent().unwrap_or_else(|| Path::new("/"));
+
+    let source_components: Vec<Component> = source.components().collect();
+    let dir_components: Vec<Component> = dir.components().collect();
+    let nr_common = source_components
+        .iter()
+        .zip(&dir_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative_path = PathBuf::new();
+    for _ in nr_common..dir_components.len() {
+        relative_path.push("..");
+    }
+    for component in &source_components[nr_common..] {
+        relative_path.push(component);
+    }
+    Ok(relative_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_unpinned() {
+        let content = "FROM golang:1.21-alpine AS build\n\
+                       FROM build\n\
+                       FROM scratch\n\
+                       # [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n\
+                       FROM testapp\n\
+                       FROM testapp@sha256:1\n\
+                       FROM registry:5000/node\n";
+
+        assert_eq!(
+            find_unpinned_images(content),
+            [
+                UnpinnedImage {
+                    line_nr: 1,
+                    repository: "golang".to_owned(),
+                    tag: Some("1.21-alpine".to_owned()),
+                },
+                UnpinnedImage {
+                    line_nr: 7,
+                    repository: "registry:5000/node".to_owned(),
+                    tag: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn candidate_reqs() {
+        assert_eq!(
+            get_candidate_reqs(&Some("1.21-alpine".to_owned())),
+            [
+                ("@1.21-alpine".to_owned(), "".to_owned()),
+                ("~1.21".to_owned(), "scheme=loose,variant=alpine".to_owned()),
+            ]
+        );
+        assert_eq!(
+            get_candidate_reqs(&Some("1.21.5".to_owned()))[1],
+            ("~1.21.5".to_owned(), "".to_owned())
+        );
+        assert_eq!(
+            get_candidate_reqs(&Some("1.2.3.4".to_owned()))[1],
+            ("~1.2.3.4".to_owned(), "scheme=dotted".to_owned())
+        );
+        assert_eq!(
+            get_candidate_reqs(&None),
+            [
+                ("@latest".to_owned(), "".to_owned()),
+                ("*".to_owned(), "".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn propose_and_apply() {
+        let now = crate::syntax::parse_timestamp("2021-01-02T03-04-05").unwrap();
+        let policy = ResolvePolicy::new(now);
+        let filename = "./test/init/app/Dockerfile";
+
+        let FileProposals {
+            proposals,
+            failures,
+        } = propose_tags(filename, "./test/init/versions.ini", &policy).unwrap();
+        assert_eq!(proposals.len(), 2);
+        assert_eq!(proposals[0].new_tag.version_req, "@stable");
+        assert_eq!(proposals[0].new_tag.version_source, "file(../versions.ini)");
+        assert_eq!(proposals[1].new_tag.version_req, "~1.0");
+        assert_eq!(proposals[1].new_tag.attributes, "scheme=loose");
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0.repository, "unknown");
+
+        let proposals: Vec<&Proposal> = proposals.iter().collect();
+        let text_file = apply_proposals(filename, &proposals, &policy).unwrap();
+        assert_eq!(
+            text_file.content,
+            "# [demver(@stable)|file(../versions.ini)|testapp] 1.5.0 @ 2021-01-02T03-04-05\n\
+             FROM testapp@sha256:15 AS build\n\
+             # [demver(~1.0)|file(../versions.ini)|worker|scheme=loose] 1.0.2 @ 2021-01-02T03-04-05\n\
+             FROM worker@sha256:w102\n\
+             FROM unknown:1.0\n"
+        );
+    }
+
+    #[test]
+    fn propose_two_part_versions() {
+        let now = crate::syntax::parse_timestamp("2021-01-02T03-04-05").unwrap();
+        let policy = ResolvePolicy::new(now);
+        let filename = "./test/init/docker/Dockerfile";
+
+        let FileProposals {
+            proposals,
+            failures,
+        } = propose_tags(filename, "./test/init/docker/versions.ini", &policy).unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(proposals.len(), 1);
+
+        let proposals: Vec<&Proposal> = proposals.iter().collect();
+        let text_file = apply_proposals(filename, &proposals, &policy).unwrap();
+        assert_eq!(
+            text_file.content,
+            "# [demver(~1.21)|file(versions.ini)|golang|scheme=loose,variant=alpine] 1.21.5-alpine @ 2021-01-02T03-04-05\n\
+             FROM golang@sha256:g1215a\n"
+        );
+    }
+}
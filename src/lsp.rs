@@ -0,0 +1,582 @@
+use crate::policy::ResolvePolicy;
+use crate::source_session;
+use crate::syntax::{DemverTag, SourceTag, TagAttributes};
+use crate::tag_checker::TagChecker;
+use crate::tag_parser;
+use crate::tag_updater::{TagUpdater, UpdateOptions};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, LogMessage,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{CodeActionRequest, Completion, HoverRequest, Request as RequestTrait};
+use lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams,
+    CodeActionProviderCapability, CompletionItem, CompletionItemKind, CompletionOptions,
+    CompletionParams, CompletionResponse, Diagnostic, DiagnosticSeverity, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, LogMessageParams, MarkupContent, MarkupKind, MessageType,
+    Position, PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Url, WorkspaceEdit,
+};
+use std::collections::HashMap;
+
+/// Serves the language server protocol over stdin and stdout until the
+/// client shuts it down.
+pub fn run() -> Result<(), String> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["|".to_owned(), "=".to_owned(), " ".to_owned()]),
+            ..CompletionOptions::default()
+        }),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        ..ServerCapabilities::default()
+    };
+    let capabilities = serde_json::to_value(capabilities).map_err(|e| e.to_string())?;
+    connection
+        .initialize(capabilities)
+        .map_err(|e| format!("failed to initialize the language server: {}", e))?;
+
+    Server {
+        connection,
+        documents: HashMap::new(),
+    }
+    .run()?;
+
+    io_threads
+        .join()
+        .map_err(|e| format!("language server I/O failed: {}", e))
+}
+
+struct Server {
+    connection: Connection,
+    /// the content of all open documents, which may differ from the disk
+    documents: HashMap<Url, String>,
+}
+
+impl Server {
+    fn run(mut self) -> Result<(), String> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    let is_shutdown = self
+                        .connection
+                        .handle_shutdown(&request)
+                        .map_err(|e| e.to_string())?;
+                    if is_shutdown {
+                        return Ok(());
+                    }
+                    let response = self.handle_request(request);
+                    self.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => {
+                    // a malformed notification must not end the session
+                    let method = notification.method.clone();
+                    if let Err(err_msg) = self.handle_notification(notification) {
+                        self.log_error(&format!("failed to handle {}: {}", method, err_msg))?;
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, message: Message) -> Result<(), String> {
+        self.connection
+            .sender
+            .send(message)
+            .map_err(|e| format!("failed to send to the client: {}", e))
+    }
+
+    fn log_error(&self, message: &str) -> Result<(), String> {
+        let params = LogMessageParams {
+            typ: MessageType::ERROR,
+            message: message.to_owned(),
+        };
+        self.send(Message::Notification(Notification::new(
+            LogMessage::METHOD.to_owned(),
+            params,
+        )))
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        // sources may have changed on disk since the last request
+        source_session::clear();
+        let Request { id, method, params } = request;
+        let result = match method.as_str() {
+            HoverRequest::METHOD => self.dispatch::<HoverRequest>(params, Self::on_hover),
+            Completion::METHOD => self.dispatch::<Completion>(params, Self::on_completion),
+            CodeActionRequest::METHOD => {
+                self.dispatch::<CodeActionRequest>(params, Self::on_code_action)
+            }
+            _ => return error_response(id, ErrorCode::MethodNotFound, "unknown request"),
+        };
+        match result {
+            Ok(value) => Response::new_ok(id, value),
+            Err(err_msg) => error_response(id, ErrorCode::InvalidParams, &err_msg),
+        }
+    }
+
+    fn dispatch<R: RequestTrait>(
+        &self,
+        params: serde_json::Value,
+        handler: impl Fn(&Self, R::Params) -> R::Result,
+    ) -> Result<serde_json::Value, String> {
+        let params = serde_json::from_value(params).map_err(|e| e.to_string())?;
+        serde_json::to_value(handler(self, params)).map_err(|e| e.to_string())
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<(), String> {
+        let params = notification.params;
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: <DidOpenTextDocument as NotificationTrait>::Params =
+                    serde_json::from_value(params).map_err(|e| e.to_string())?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: <DidChangeTextDocument as NotificationTrait>::Params =
+                    serde_json::from_value(params).map_err(|e| e.to_string())?;
+                let uri = params.text_document.uri;
+                // the whole document is synced, so the last change has it all
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: <DidCloseTextDocument as NotificationTrait>::Params =
+                    serde_json::from_value(params).map_err(|e| e.to_string())?;
+                self.documents.remove(&params.text_document.uri);
+                params.text_document.uri
+            }
+            _ => return Ok(()),
+        };
+
+        let diagnostics = match self.documents.get(&uri) {
+            Some(content) => get_diagnostics(&get_filename(&uri), content),
+            None => vec![],
+        };
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        self.send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_owned(),
+            params,
+        )))
+    }
+
+    fn on_hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let content = self.documents.get(&position.text_document.uri)?;
+        let offset = get_offset(content, position.position);
+        let filename = get_filename(&position.text_document.uri);
+        let tag = find_tag(&filename, content, offset)?;
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: get_hover_text(&tag, &ResolvePolicy::new(chrono::Utc::now())),
+            }),
+            range: Some(get_range(
+                content,
+                tag.get_tokenized_tag().get_idx_start(),
+                tag.get_tokenized_tag().get_idx_end(),
+            )),
+        })
+    }
+
+    fn on_completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let position = params.text_document_position;
+        let content = self.documents.get(&position.text_document.uri)?;
+        let offset = get_offset(content, position.position);
+        let filename = get_filename(&position.text_document.uri);
+        let items = get_completions(&filename, content, offset);
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn on_code_action(&self, params: CodeActionParams) -> Option<Vec<CodeActionOrCommand>> {
+        let uri = params.text_document.uri;
+        let content = self.documents.get(&uri)?;
+        let start = get_offset(content, params.range.start);
+        let end = get_offset(content, params.range.end);
+        Some(get_code_actions(&uri, content, start, end))
+    }
+}
+
+fn error_response(id: RequestId, code: ErrorCode, message: &str) -> Response {
+    Response::new_err(id, code as i32, message.to_owned())
+}
+
+fn get_filename(uri: &Url) -> String {
+    match uri.to_file_path() {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => uri.path().to_owned(),
+    }
+}
+
+/// Converts a byte offset to a position, which counts UTF-16 code units.
+fn get_position(content: &str, offset: usize) -> Position {
+    let before = &content[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+fn get_offset(content: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match content[line_start..].find('\n') {
+            Some(idx) => line_start += idx + 1,
+            None => return content.len(),
+        }
+    }
+    let line_end = content[line_start..]
+        .find('\n')
+        .map_or(content.len(), |idx| line_start + idx);
+
+    let mut units = 0;
+    for (idx, c) in content[line_start..line_end].char_indices() {
+        if units >= position.character {
+            return line_start + idx;
+        }
+        units += c.len_utf16() as u32;
+    }
+    line_end
+}
+
+fn get_range(content: &str, start: usize, end: usize) -> Range {
+    Range::new(get_position(content, start), get_position(content, end))
+}
+
+/// Every tag that can't be parsed, marking the word the error points at.
+fn get_diagnostics(filename: &str, content: &str) -> Vec<Diagnostic> {
+    DemverTag::parse_all(filename, content)
+        .into_iter()
+        .filter_map(Result::err)
+        .map(|err| {
+            let end = content[err.offset..]
+                .find(char::is_whitespace)
+                .map_or(content.len(), |idx| err.offset + idx);
+            Diagnostic {
+                range: get_range(content, err.offset, end),
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("demver".to_owned()),
+                message: err.message,
+                ..Diagnostic::default()
+            }
+        })
+        .collect()
+}
+
+fn find_tag(filename: &str, content: &str, offset: usize) -> Option<DemverTag> {
+    DemverTag::parse_all(filename, content)
+        .into_iter()
+        .flatten()
+        .find(|tag| {
+            let tokenized_tag = tag.get_tokenized_tag();
+            (tokenized_tag.get_idx_start()..=tokenized_tag.get_idx_end()).contains(&offset)
+        })
+}
+
+/// The pinned and the newest matching version of a tag, with their hashes
+/// and the age of the pin.
+fn get_hover_text(tag: &DemverTag, policy: &ResolvePolicy) -> String {
+    let describe = |result: Result<crate::version::FixedVersion, String>| match result {
+        Ok(version) => format!("{} `{}`", version.raw_version, version.get_hash()),
+        Err(err_msg) => format!("error: {}", err_msg),
+    };
+
+    format!(
+        "**{}** `{}`\n\npinned: {}, {} old\n\nnewest matching: {}",
+        tag.identifier,
+        tag.get_raw_version_req(),
+        describe(TagChecker::get_pinned_version_from_source(tag)),
+        crate::age::format_age(tag.get_age(policy.get_now())),
+        describe(TagChecker::get_current_version_from_source(tag, policy)),
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum CompletionField {
+    Identifier,
+    Version,
+}
+
+/// What is being typed at the end of a line, and the fields of the tag
+/// written so far, which need not be a valid tag yet.
+#[derive(Debug, PartialEq)]
+struct CompletionContext {
+    field: CompletionField,
+    version_source: String,
+    identifier: String,
+    attributes: String,
+}
+
+fn get_completion_context(line: &str) -> Option<CompletionContext> {
+    let tag = &line[line.rfind("[demver")? + "[demver".len()..];
+    let (fields, trailer) = match tag.split_once(']') {
+        Some((fields, trailer)) => (fields, Some(trailer)),
+        None => (tag, None),
+    };
+
+    let mut version_source = String::new();
+    let mut identifier = String::new();
+    let mut attributes = vec![];
+    let mut field = None;
+    if let Some(fields) = fields.strip_prefix(":2") {
+        for (key, value) in fields.split_whitespace().filter_map(|f| f.split_once('=')) {
+            let value = tag_parser::unquote(value).unwrap_or_else(|_| value.to_owned());
+            match key {
+                "src" => version_source = value,
+                "id" => identifier = value,
+                k if TagAttributes::KEYS.contains(&k) => {
+                    attributes.push(format!("{}={}", k, value))
+                }
+                _ => {}
+            }
+        }
+        let last = fields.rsplit(char::is_whitespace).next().unwrap_or("");
+        if trailer.is_none() && last.starts_with("id=") {
+            field = Some(CompletionField::Identifier);
+        }
+    } else {
+        let parts: Vec<&str> = fields.split('|').collect();
+        version_source = parts.get(1).unwrap_or(&"").trim().to_owned();
+        identifier = parts.get(2).unwrap_or(&"").trim().to_owned();
+        attributes.extend(parts.get(3).map(|a| a.trim().to_owned()));
+        if trailer.is_none() && parts.len() == 3 {
+            field = Some(CompletionField::Identifier);
+        }
+    }
+    // the pinned version follows the tag, before the timestamp
+    if trailer.is_some_and(|t| {
+        t.starts_with(' ') && !t.contains('@') && t.split_whitespace().count() <= 1
+    }) {
+        field = Some(CompletionField::Version);
+    }
+
+    Some(CompletionContext {
+        field: field?,
+        version_source,
+        identifier,
+        attributes: attributes.join(","),
+    })
+}
+
+/// Identifiers or versions from the source of the tag being typed.
+fn get_completions(filename: &str, content: &str, offset: usize) -> Vec<CompletionItem> {
+    let line_start = content[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+    let context = match get_completion_context(&content[line_start..offset]) {
+        Some(context) => context,
+        None => return vec![],
+    };
+    let version_sources = match SourceTag::parse_list(&context.version_source) {
+        Ok(version_sources) => version_sources,
+        Err(_) => return vec![],
+    };
+
+    match context.field {
+        CompletionField::Identifier => {
+            TagChecker::get_identifiers_from_source(filename, &version_sources)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|identifier| CompletionItem {
+                    label: identifier,
+                    kind: Some(CompletionItemKind::VALUE),
+                    ..CompletionItem::default()
+                })
+                .collect()
+        }
+        CompletionField::Version => {
+            let format = TagAttributes::parse(&context.attributes)
+                .unwrap_or_default()
+                .get_format();
+            TagChecker::get_versions_from_source(
+                filename,
+                &version_sources,
+                &context.identifier,
+                &format,
+            )
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, version)| CompletionItem {
+                label: version.raw_version.clone(),
+                kind: Some(CompletionItemKind::VALUE),
+                detail: Some(version.get_hash().to_owned()),
+                // keep the newest version on top
+                sort_text: Some(format!("{:05}", idx)),
+                ..CompletionItem::default()
+            })
+            .collect()
+        }
+    }
+}
+
+/// Offers to update each tag on the lines of the range to the newest
+/// matching version, together with the hash in its target.
+fn get_code_actions(
+    uri: &Url,
+    content: &str,
+    start: usize,
+    end: usize,
+) -> Vec<CodeActionOrCommand> {
+    let filename = get_filename(uri);
+    let start = content[..start].rfind('\n').map_or(0, |idx| idx + 1);
+    let end = content[end..]
+        .find('\n')
+        .map_or(content.len(), |idx| end + idx);
+    let updater = TagUpdater::new(
+        ResolvePolicy::new(chrono::Utc::now()),
+        UpdateOptions::default(),
+    );
+
+    let mut actions = vec![];
+    for tag in DemverTag::parse_all(&filename, content)
+        .into_iter()
+        .flatten()
+    {
+        let tokenized_tag = tag.get_tokenized_tag();
+        if tokenized_tag.get_idx_end() < start || tokenized_tag.get_idx_start() > end {
+            continue;
+        }
+
+        let mut edits = vec![];
+        let tag_update = match updater.update_tag(&tag, content, &mut edits) {
+            Ok(Some(tag_update)) => tag_update,
+            _ => continue,
+        };
+        let text_edits = edits
+            .into_iter()
+            .map(|edit| TextEdit {
+                range: get_range(content, edit.idx_start, edit.idx_end),
+                new_text: edit.replacement,
+            })
+            .collect();
+
+        actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+            title: format!(
+                "Update {} from {} to {}",
+                tag_update.identifier, tag_update.old_version, tag_update.new_version
+            ),
+            kind: Some(CodeActionKind::QUICKFIX),
+            edit: Some(WorkspaceEdit {
+                changes: Some(HashMap::from([(uri.clone(), text_edits)])),
+                ..WorkspaceEdit::default()
+            }),
+            ..CodeAction::default()
+        }));
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILENAME: &str = "./test/add/default.env";
+    const CONTENT: &str =
+        "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n\
+                           TESTAPP=sha256:1\n";
+
+    #[test]
+    fn position_offset() {
+        let content = "ab\nc\u{1F600}d\n";
+
+        assert_eq!(get_position(content, 8), Position::new(1, 3));
+        assert_eq!(get_offset(content, Position::new(1, 3)), 8);
+        assert_eq!(get_offset(content, Position::new(1, 99)), 9);
+        assert_eq!(get_offset(content, Position::new(9, 0)), content.len());
+    }
+
+    #[test]
+    fn diagnostics() {
+        let content = "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @\n";
+
+        let diagnostics = get_diagnostics(FILENAME, content);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "missing timestamp after '@'");
+        assert_eq!(diagnostics[0].range.start, Position::new(0, 53));
+        assert!(get_diagnostics(FILENAME, CONTENT).is_empty());
+    }
+
+    #[test]
+    fn hover() {
+        let tag = find_tag(FILENAME, CONTENT, 10).unwrap();
+        let now = crate::syntax::parse_timestamp("2021-01-02T03-04-05").unwrap();
+
+        assert_eq!(
+            get_hover_text(&tag, &ResolvePolicy::new(now)),
+            "**testapp** `^1.0.0`\n\npinned: 1.0.0 `sha256:1`, 27d old\n\nnewest matching: 1.5.0 `sha256:15`"
+        );
+        assert!(find_tag(FILENAME, CONTENT, CONTENT.len() - 2).is_none());
+    }
+
+    #[test]
+    fn completion_context() {
+        let context = get_completion_context("# [demver(^1.0.0)|file(versions.ini)|tes").unwrap();
+        assert_eq!(context.field, CompletionField::Identifier);
+        assert_eq!(context.version_source, "file(versions.ini)");
+
+        let context = get_completion_context(
+            "# [demver:2 req=^1 src=file(versions.ini) id=testapp variant=alpine] 1.",
+        )
+        .unwrap();
+        assert_eq!(context.field, CompletionField::Version);
+        assert_eq!(context.identifier, "testapp");
+        assert_eq!(context.attributes, "variant=alpine");
+
+        assert!(
+            get_completion_context("# [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ ")
+                .is_none()
+        );
+        assert!(get_completion_context("# [demver(^1.0.0").is_none());
+    }
+
+    #[test]
+    fn completions() {
+        let content = "# [demver(^1.0.0)|file(versions.ini)|testapp] ";
+
+        let labels: Vec<String> = get_completions(FILENAME, content, content.len())
+            .into_iter()
+            .map(|item| item.label)
+            .collect();
+        assert_eq!(labels, ["2.0.0", "1.5.0", "1.0.0"]);
+
+        let content = "# [demver(^1.0.0)|file(versions.ini)|";
+        let items = get_completions(FILENAME, content, content.len());
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "testapp");
+    }
+
+    #[test]
+    fn code_actions() {
+        let uri = Url::from_file_path(std::fs::canonicalize(".").unwrap().join(FILENAME)).unwrap();
+
+        let actions = get_code_actions(&uri, CONTENT, 0, 0);
+
+        assert_eq!(actions.len(), 1);
+        let action = match &actions[0] {
+            CodeActionOrCommand::CodeAction(action) => action,
+            _ => panic!("expected a code action"),
+        };
+        assert_eq!(action.title, "Update testapp from 1.0.0 to 1.5.0");
+        let edits = &action.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri];
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].new_text, "sha256:15");
+        assert_eq!(edits[1].range.start, Position::new(1, 8));
+    }
+}
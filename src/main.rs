@@ -7,52 +7,52 @@ use std::convert::From;
 #[macro_use]
 extern crate lazy_static;
 
+mod age;
+mod comment;
 mod ini_source;
+mod init;
+mod lsp;
+mod migrate;
+mod policy;
+mod scheme;
 mod source;
+mod source_cache;
+mod source_session;
 mod syntax;
+mod tag_adder;
 mod tag_checker;
+mod tag_group;
+mod tag_parser;
 mod tag_scanner;
+mod tag_updater;
+mod target;
+mod text_file;
 mod version;
+mod watch;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use colored::*;
-
-fn get_newest_version(
-    version_range: semver::VersionReq,
-    versions: &Vec<version::FixedVersion>,
-) -> Result<&version::FixedVersion, String> {
-    let filtered_versions: Vec<&version::FixedVersion> = versions
-        .iter()
-        .filter(|v| version_range.matches(&v.get_semver()))
-        .collect();
-
-    let mut sorted_versions = filtered_versions.clone();
-    sorted_versions.sort();
-
-    match sorted_versions.last() {
-        Some(last) => Ok(last),
-        None => Err(String::from("oh oh")),
-    }
-}
-
-struct FileTarget {
-    filename: String,
-}
-
-impl FileTarget {
-    fn new(filename: String) -> FileTarget {
-        FileTarget { filename }
-    }
-}
+use rayon::prelude::*;
+use std::collections::BTreeSet;
 
 fn main() {
     let matches = App::new("demver")
         .version("0.1.0")
         .about("Deterministic Version Manager for reproducible builds and deployments")
         .author("Sebastian H.")
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .short('j')
+                .value_name("N")
+                .takes_value(true)
+                .global(true)
+                .about("number of files scanned and sources queried at once (default: number of CPUs)"),
+        )
         .subcommand(
             App::new("check")
                 .about("check files containing demver tags")
+                .args(cache_args())
                 .arg(
                     Arg::new("file")
                         .value_name("FILE")
@@ -60,30 +60,721 @@ fn main() {
                         .multiple(true)
                         // .index(1)
                         .about("files to check"),
+                )
+                .arg(
+                    Arg::new("max-age")
+                        .long("max-age")
+                        .value_name("AGE")
+                        .takes_value(true)
+                        .about("fail for tags that were last updated more than AGE ago, e.g. 90d"),
+                )
+                .arg(prerelease_arg())
+                .arg(Arg::new("consistent").long("consistent").about(
+                    "fail if tags of the same source and identifier pin different versions or hashes",
+                ))
+                .arg(Arg::new("watch").long("watch").about(
+                    "keep running and check the affected tags again whenever a file or source changes",
+                )),
+        )
+        .subcommand(
+            App::new("outdated")
+                .about("show the newest version within and outside of the requirement of each tag")
+                .args(cache_args())
+                .arg(prerelease_arg())
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true)
+                        .about("files to check"),
+                ),
+        )
+        .subcommand(
+            App::new("lint")
+                .about("report malformed demver tags without resolving any versions")
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true)
+                        .about("files to lint"),
+                ),
+        )
+        .subcommand(
+            App::new("lsp")
+                .about("run a language server for demver tags on stdin and stdout")
+                .args(cache_args()),
+        )
+        .subcommand(
+            App::new("migrate")
+                .about("rewrite demver tags to the version 2 syntax")
+                .arg(dry_run_arg())
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true)
+                        .about("files to migrate"),
+                ),
+        )
+        .subcommand(
+            App::new("update")
+                .about("update demver tags and their targets to the newest matching version")
+                .args(cache_args())
+                .arg(dry_run_arg())
+                .arg(
+                    Arg::new("min-age")
+                        .long("min-age")
+                        .value_name("AGE")
+                        .takes_value(true)
+                        .about("only pick versions released at least AGE ago, e.g. 7d"),
+                )
+                .arg(prerelease_arg())
+                .arg(Arg::new("consistent").long("consistent").about(
+                    "move tags of the same source and identifier to the same version",
+                ))
+                .arg(only_arg())
+                .arg(exclude_arg())
+                .arg(
+                    Arg::new("patch")
+                        .long("patch")
+                        .conflicts_with_all(&["minor", "major"])
+                        .about("only move pins to versions with the same major and minor"),
+                )
+                .arg(
+                    Arg::new("minor")
+                        .long("minor")
+                        .conflicts_with("major")
+                        .about("only move pins to versions with the same major"),
+                )
+                .arg(
+                    Arg::new("major")
+                        .long("major")
+                        .about("move pins to any version within the requirement (default)"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("VERSION")
+                        .takes_value(true)
+                        .requires("only")
+                        .about("pin this version instead of the newest, must match the requirement"),
+                )
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true)
+                        .about("files to update"),
+                ),
+        )
+        .subcommand(
+            App::new("bump-range")
+                .about("widen requirements to the newest version outside of them and re-pin")
+                .args(cache_args())
+                .arg(dry_run_arg())
+                .arg(prerelease_arg())
+                .arg(only_arg())
+                .arg(exclude_arg())
+                .arg(
+                    Arg::new("req")
+                        .long("req")
+                        .value_name("REQ")
+                        .takes_value(true)
+                        .requires("only")
+                        .about("set this requirement instead, e.g. ^2.0.0"),
+                )
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .multiple(true)
+                        .about("files to update"),
+                ),
+        )
+        .subcommand(
+            App::new("add")
+                .about("insert a tag above a line and pin the line to the resolved version")
+                .args(cache_args())
+                .arg(dry_run_arg())
+                .arg(prerelease_arg())
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true)
+                        .about("file to add the tag to"),
+                )
+                .arg(
+                    Arg::new("line")
+                        .long("line")
+                        .value_name("LINE")
+                        .takes_value(true)
+                        .required(true)
+                        .about("line to pin, starting at 1"),
+                )
+                .arg(
+                    Arg::new("req")
+                        .long("req")
+                        .value_name("REQ")
+                        .takes_value(true)
+                        .required(true)
+                        .about("version requirement, e.g. ^1.0 or @stable"),
+                )
+                .arg(
+                    Arg::new("source")
+                        .long("source")
+                        .value_name("SOURCE")
+                        .takes_value(true)
+                        .required(true)
+                        .about("version source, e.g. file(versions.ini)"),
+                )
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .value_name("ID")
+                        .takes_value(true)
+                        .required(true)
+                        .about("identifier within the source"),
+                )
+                .arg(
+                    Arg::new("attributes")
+                        .long("attributes")
+                        .value_name("ATTRIBUTES")
+                        .takes_value(true)
+                        .about("tag attributes, e.g. scheme=calver,variant=alpine"),
+                ),
+        )
+        .subcommand(
+            App::new("init")
+                .about("find unpinned images in Dockerfiles, compose files and manifests and pin them")
+                .args(cache_args())
+                .arg(dry_run_arg())
+                .arg(prerelease_arg())
+                .arg(
+                    Arg::new("source")
+                        .long("source")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .required(true)
+                        .about("ini file to look up the images in"),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .long("yes")
+                        .short('y')
+                        .about("add all proposed tags without asking"),
+                )
+                .arg(
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .required(true)
+                        .multiple(true)
+                        .about("files or directories to search"),
                 ),
         )
         .get_matches();
 
-    if let Some(ref matches) = matches.subcommand_matches("check") {
-        let files: Vec<String> = matches
-            .values_of("file")
-            .unwrap()
-            .map(|s| String::from(s))
-            .collect();
-        check(files);
+    if let Err(err_msg) = configure_jobs(&matches) {
+        eprintln!("{}: {}", "error".red().bold(), err_msg);
+        std::process::exit(1);
+    }
+    if let Some((_, sub_matches)) = matches.subcommand() {
+        if let Err(err_msg) = configure_cache(sub_matches) {
+            eprintln!("{}: {}", "error".red().bold(), err_msg);
+            std::process::exit(1);
+        }
+    }
+
+    let result = if let Some(matches) = matches.subcommand_matches("check") {
+        run_check(matches)
+    } else if let Some(matches) = matches.subcommand_matches("outdated") {
+        run_outdated(matches)
+    } else if let Some(matches) = matches.subcommand_matches("lint") {
+        lint(get_files(matches))
+    } else if matches.subcommand_matches("lsp").is_some() {
+        lsp::run()
+    } else if let Some(matches) = matches.subcommand_matches("migrate") {
+        migrate(get_files(matches), matches.is_present("dry-run"))
+    } else if let Some(matches) = matches.subcommand_matches("update") {
+        run_update(matches)
+    } else if let Some(matches) = matches.subcommand_matches("bump-range") {
+        run_bump_range(matches)
+    } else if let Some(matches) = matches.subcommand_matches("add") {
+        run_add(matches)
+    } else if let Some(matches) = matches.subcommand_matches("init") {
+        run_init(matches)
+    } else {
+        Ok(())
+    };
+
+    if let Err(err_msg) = result {
+        eprintln!("{}: {}", "error".red().bold(), err_msg);
+        std::process::exit(1);
+    }
+}
+
+fn prerelease_arg() -> Arg<'static> {
+    Arg::new("prerelease")
+        .long("prerelease")
+        .value_name("POLICY")
+        .takes_value(true)
+        .about("pre-releases to consider: exclude, same-release or a channel like rc")
+}
+
+fn dry_run_arg() -> Arg<'static> {
+    Arg::new("dry-run")
+        .long("dry-run")
+        .about("print a unified diff instead of writing, fail if anything would change")
+}
+
+fn only_arg() -> Arg<'static> {
+    Arg::new("only")
+        .long("only")
+        .value_name("ID")
+        .takes_value(true)
+        .multiple_occurrences(true)
+        .about("only update tags with this identifier, can be repeated")
+}
+
+fn exclude_arg() -> Arg<'static> {
+    Arg::new("exclude")
+        .long("exclude")
+        .value_name("ID")
+        .takes_value(true)
+        .multiple_occurrences(true)
+        .about("don't update tags with this identifier, can be repeated")
+}
+
+/// Options of the on-disk cache of remote sources. No source is remote yet,
+/// so they have no effect so far.
+fn cache_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("offline")
+            .long("offline")
+            .conflicts_with("refresh")
+            .about("resolve remote sources from the cache only (no effect yet, all sources are local files)"),
+        Arg::new("refresh")
+            .long("refresh")
+            .about("ignore cached remote sources and fetch them again (no effect yet, all sources are local files)"),
+        Arg::new("cache-ttl")
+            .long("cache-ttl")
+            .value_name("AGE")
+            .takes_value(true)
+            .about("how long cached remote sources are used, e.g. 12h (no effect yet, all sources are local files)"),
+    ]
+}
+
+fn configure_cache(matches: &ArgMatches) -> Result<(), String> {
+    let mut options = source_cache::CacheOptions::default();
+    if matches.is_present("offline") {
+        options.mode = source_cache::CacheMode::Offline;
+    } else if matches.is_present("refresh") {
+        options.mode = source_cache::CacheMode::Refresh;
+    }
+    if let Some(ttl) = matches.value_of("cache-ttl") {
+        options.ttl = age::parse_age(ttl)?;
+    }
+    source_cache::configure(options);
+    Ok(())
+}
+
+/// Bounds the worker pool that scans files and queries sources.
+fn configure_jobs(matches: &ArgMatches) -> Result<(), String> {
+    let jobs = match matches.value_of("jobs") {
+        Some(jobs) => jobs
+            .parse::<usize>()
+            .ok()
+            .filter(|jobs| *jobs > 0)
+            .ok_or_else(|| format!("invalid number of jobs: {}", jobs))?,
+        None => return Ok(()),
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build_global()
+        .map_err(|e| e.to_string())
+}
+
+fn get_files(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .values_of("file")
+        .unwrap()
+        .map(String::from)
+        .collect()
+}
+
+fn get_values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Builds the resolve policy from the arguments shared by all subcommands.
+fn get_policy(matches: &ArgMatches) -> Result<policy::ResolvePolicy, String> {
+    let prerelease = matches
+        .value_of("prerelease")
+        .map(policy::PrereleasePolicy::parse)
+        .transpose()?;
+
+    Ok(policy::ResolvePolicy::new(chrono::Utc::now()).with_prerelease(prerelease))
+}
+
+fn run_check(matches: &ArgMatches) -> Result<(), String> {
+    let max_age = matches
+        .value_of("max-age")
+        .map(age::parse_age)
+        .transpose()?;
+    if matches.is_present("watch") {
+        return check_watch(
+            get_files(matches),
+            || get_policy(matches),
+            max_age,
+            matches.is_present("consistent"),
+        );
+    }
+    check(
+        get_files(matches),
+        get_policy(matches)?,
+        max_age,
+        matches.is_present("consistent"),
+    )
+}
+
+fn run_outdated(matches: &ArgMatches) -> Result<(), String> {
+    outdated(get_files(matches), get_policy(matches)?)
+}
+
+fn run_update(matches: &ArgMatches) -> Result<(), String> {
+    let min_age = matches
+        .value_of("min-age")
+        .map(age::parse_age)
+        .transpose()?;
+    let bump = if matches.is_present("patch") {
+        Some(policy::BumpLevel::Patch)
+    } else if matches.is_present("minor") {
+        Some(policy::BumpLevel::Minor)
+    } else if matches.is_present("major") {
+        Some(policy::BumpLevel::Major)
+    } else {
+        None
+    };
+    let policy = get_policy(matches)?.with_min_age(min_age).with_bump(bump);
+    let options = tag_updater::UpdateOptions {
+        only: get_values(matches, "only"),
+        exclude: get_values(matches, "exclude"),
+        to_version: matches.value_of("to").map(String::from),
+        bump_range: None,
+    };
+    update(
+        get_files(matches),
+        policy,
+        options,
+        matches.is_present("dry-run"),
+        matches.is_present("consistent"),
+    )
+}
+
+fn run_bump_range(matches: &ArgMatches) -> Result<(), String> {
+    let bump_range = match matches.value_of("req") {
+        Some(version_req) => tag_updater::RangeBump::To(version_req.to_owned()),
+        None => tag_updater::RangeBump::Newest,
+    };
+    let options = tag_updater::UpdateOptions {
+        only: get_values(matches, "only"),
+        exclude: get_values(matches, "exclude"),
+        to_version: None,
+        bump_range: Some(bump_range),
+    };
+    update(
+        get_files(matches),
+        get_policy(matches)?,
+        options,
+        matches.is_present("dry-run"),
+        false,
+    )
+}
+
+fn run_add(matches: &ArgMatches) -> Result<(), String> {
+    let line_nr = matches.value_of("line").unwrap();
+    let line_nr = line_nr
+        .parse()
+        .map_err(|_| format!("invalid line number '{}'", line_nr))?;
+    let new_tag = tag_adder::NewTag {
+        version_req: matches.value_of("req").unwrap().to_owned(),
+        version_source: matches.value_of("source").unwrap().to_owned(),
+        identifier: matches.value_of("id").unwrap().to_owned(),
+        attributes: matches.value_of("attributes").unwrap_or("").to_owned(),
+    };
+    let file_update = tag_adder::add_tag(
+        matches.value_of("file").unwrap(),
+        line_nr,
+        &new_tag,
+        &get_policy(matches)?,
+    )?;
+
+    if matches.is_present("dry-run") {
+        print!("{}", file_update.unified_diff());
+        if file_update.has_changes() {
+            return Err("1 file(s) would be changed".to_owned());
+        }
+    } else {
+        file_update.write()?;
+        println!(
+            "{}: added tag for {} in line {}",
+            file_update.filename.green().bold(),
+            new_tag.identifier,
+            line_nr
+        );
+    }
+    Ok(())
+}
+
+fn run_init(matches: &ArgMatches) -> Result<(), String> {
+    let paths: Vec<String> = get_values(matches, "path");
+    let files = init::find_candidate_files(&paths)?;
+    let source = matches.value_of("source").unwrap();
+    let policy = get_policy(matches)?;
+    let dry_run = matches.is_present("dry-run");
+    let ask = !matches.is_present("yes") && !dry_run;
+
+    let mut nr_added = 0;
+    for filename in &files {
+        let init::FileProposals {
+            proposals,
+            failures,
+        } = match init::propose_tags(filename, source, &policy) {
+            Ok(file_proposals) => file_proposals,
+            Err(err_msg) => {
+                eprintln!("{}: ERROR {}", filename.red().bold(), err_msg);
+                continue;
+            }
+        };
+        if proposals.is_empty() && failures.is_empty() {
+            continue;
+        }
+
+        println!("{}: ", filename.green().bold());
+        for (image, err_msg) in &failures {
+            println!(
+                "  line {}: {} skipped ({})",
+                image.line_nr, image.repository, err_msg
+            );
+        }
+        let mut accepted = vec![];
+        for proposal in &proposals {
+            let description = format!(
+                "  line {}: {} {} [{}]",
+                proposal.image.line_nr,
+                proposal.new_tag.identifier,
+                proposal.new_tag.version_req,
+                proposal.new_tag.version_source
+            );
+            if !ask {
+                println!("{}", description);
+                accepted.push(proposal);
+            } else if confirm(&format!("{}, add tag?", description))? {
+                accepted.push(proposal);
+            }
+        }
+        if accepted.is_empty() {
+            continue;
+        }
+
+        let text_file = init::apply_proposals(filename, &accepted, &policy)?;
+        let file_update = tag_updater::FileUpdate {
+            filename: filename.clone(),
+            text_file: text_file::TextFile::read(filename)?,
+            updated: text_file.content,
+            tag_update_results: vec![],
+            notes: vec![],
+        };
+        if dry_run {
+            print!("{}", file_update.unified_diff());
+        } else {
+            file_update.write()?;
+        }
+        nr_added += accepted.len();
+    }
+
+    if dry_run {
+        if nr_added > 0 {
+            return Err(format!("{} tag(s) would be added", nr_added));
+        }
+    } else {
+        println!("added {} tag(s)", nr_added);
     }
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool, String> {
+    use std::io::Write;
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().map_err(|e| e.to_string())?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| e.to_string())?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
 }
 
-fn check(files: Vec<String>) -> Result<(), String> {
+fn check(
+    files: Vec<String>,
+    policy: policy::ResolvePolicy,
+    max_age: Option<chrono::Duration>,
+    consistent: bool,
+) -> Result<(), String> {
+    let scanner = tag_scanner::TagScanner::new(files);
+    check_scanned(&scanner.do_scan(), &policy, max_age, consistent)
+}
+
+fn check_scanned(
+    file_infos: &[tag_scanner::FileInfo],
+    policy: &policy::ResolvePolicy,
+    max_age: Option<chrono::Duration>,
+    consistent: bool,
+) -> Result<(), String> {
+    let mut nr_stale_tags = 0;
+
+    let tag_infos = tag_scanner::map_tags(file_infos, |tag_version_result| {
+        format_tag_version_info(tag_version_result, policy, max_age)
+    });
+    for (file_info, tag_infos) in file_infos.iter().zip(tag_infos) {
+        match &file_info.version_result {
+            Ok(_) => {
+                println!("{}: ", file_info.filename.green().bold());
+                for (line, is_stale) in tag_infos {
+                    println!("{}", line);
+                    if is_stale {
+                        nr_stale_tags += 1;
+                    }
+                }
+            }
+            Err(err_msg) => {
+                println!("{}: ERROR {}", file_info.filename.red().bold(), err_msg);
+            }
+        }
+    }
+
+    let nr_inconsistent_groups = if consistent {
+        check_consistency(file_infos)
+    } else {
+        0
+    };
+
+    let mut err_msgs = vec![];
+    if nr_stale_tags > 0 {
+        err_msgs.push(format!(
+            "{} tag(s) were not updated for more than {}",
+            nr_stale_tags,
+            age::format_age(max_age.unwrap())
+        ));
+    }
+    if nr_inconsistent_groups > 0 {
+        err_msgs.push(format!(
+            "{} group(s) of tags are inconsistent",
+            nr_inconsistent_groups
+        ));
+    }
+    if !err_msgs.is_empty() {
+        return Err(err_msgs.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Checks all files once, then checks the affected tags again whenever a
+/// scanned file or a source changes, until interrupted.
+fn check_watch(
+    files: Vec<String>,
+    get_policy: impl Fn() -> Result<policy::ResolvePolicy, String>,
+    max_age: Option<chrono::Duration>,
+    consistent: bool,
+) -> Result<(), String> {
+    let mut file_infos = tag_scanner::TagScanner::new(files).do_scan();
+    if let Err(err_msg) = check_scanned(&file_infos, &get_policy()?, max_age, consistent) {
+        eprintln!("{}: {}", "error".red().bold(), err_msg);
+    }
+
+    let mut watcher = watch::FileWatcher::new()?;
+    loop {
+        watcher.watch(watch::get_dependencies(&file_infos))?;
+        let changed = watcher.wait()?;
+        source_session::clear();
+        let policy = get_policy()?;
+
+        println!(
+            "{}",
+            format!("--- {} ---", chrono::Local::now().format("%H:%M:%S")).bold()
+        );
+        for (idx, affected) in watch::apply_changes(&mut file_infos, &changed) {
+            let file_info = &file_infos[idx];
+            let file_version = match &file_info.version_result {
+                Ok(file_version) => file_version,
+                Err(err_msg) => {
+                    println!("{}: ERROR {}", file_info.filename.red().bold(), err_msg);
+                    continue;
+                }
+            };
+            println!("{}: ", file_info.filename.green().bold());
+            for (tag_idx, tag_version_result) in file_version.tag_version_results.iter().enumerate()
+            {
+                let is_affected = match &affected {
+                    watch::Affected::All => true,
+                    watch::Affected::Tags(tag_indices) => tag_indices.contains(&tag_idx),
+                };
+                if is_affected {
+                    let (line, _) = format_tag_version_info(tag_version_result, &policy, max_age);
+                    println!("{}", line);
+                }
+            }
+        }
+        if consistent {
+            check_consistency(&file_infos);
+        }
+    }
+}
+
+/// Prints tags of the same source and identifier that disagree, returns the
+/// number of inconsistent groups.
+fn check_consistency(file_infos: &[tag_scanner::FileInfo]) -> usize {
+    let mut nr_inconsistent_groups = 0;
+
+    for group in tag_group::group_by_source(file_infos) {
+        let inconsistencies = group.find_inconsistencies();
+        if inconsistencies.is_empty() {
+            continue;
+        }
+        nr_inconsistent_groups += 1;
+        println!("{} {}", group.name.red().bold(), "INCONSISTENT".red());
+        for inconsistency in inconsistencies {
+            println!("  {}", inconsistency);
+        }
+    }
+
+    nr_inconsistent_groups
+}
+
+fn outdated(files: Vec<String>, policy: policy::ResolvePolicy) -> Result<(), String> {
     let scanner = tag_scanner::TagScanner::new(files);
     let file_infos = scanner.do_scan();
 
-    for file_info in &file_infos {
+    let mut nr_out_of_range = 0;
+
+    let tag_infos = tag_scanner::map_tags(&file_infos, |tag_version_result| {
+        format_outdated_info(tag_version_result, &policy)
+    });
+    for (file_info, tag_infos) in file_infos.iter().zip(tag_infos) {
         match &file_info.version_result {
-            Ok(file_version) => {
+            Ok(_) => {
                 println!("{}: ", file_info.filename.green().bold());
-                for tag_version_result in &file_version.tag_version_results {
-                    print_tag_version_info(tag_version_result)
+                for (line, is_out_of_range) in tag_infos {
+                    println!("{}", line);
+                    if is_out_of_range {
+                        nr_out_of_range += 1;
+                    }
                 }
             }
             Err(err_msg) => {
@@ -92,27 +783,390 @@ fn check(files: Vec<String>) -> Result<(), String> {
         }
     }
 
+    if nr_out_of_range > 0 {
+        println!(
+            "{} tag(s) have newer versions outside of their requirement",
+            nr_out_of_range
+        );
+    }
+
+    Ok(())
+}
+
+/// Reports every tag that can't be parsed, as `file:line:column: message`.
+fn lint(files: Vec<String>) -> Result<(), String> {
+    let scanner = tag_scanner::TagScanner::new(files);
+    let mut nr_problems = 0;
+
+    for file_info in scanner.do_scan() {
+        let tag_version_results = match file_info.version_result {
+            Ok(file_version) => file_version.tag_version_results,
+            Err(err_msg) => vec![Err(format!("{}: {}", file_info.filename, err_msg))],
+        };
+        for err_msg in tag_version_results.into_iter().filter_map(Result::err) {
+            println!("{}", err_msg);
+            nr_problems += 1;
+        }
+    }
+
+    match nr_problems {
+        0 => Ok(()),
+        n => Err(format!("{} problem(s) found", n)),
+    }
+}
+
+fn migrate(files: Vec<String>, dry_run: bool) -> Result<(), String> {
+    let scanner = tag_scanner::TagScanner::new(files);
+    let mut nr_changed_files = 0;
+    let mut nr_errors = 0;
+
+    for file_info in scanner.do_scan() {
+        let migration = match migrate::migrate_file(&file_info) {
+            Ok(migration) => migration,
+            Err(err_msg) => {
+                eprintln!("{}: ERROR {}", file_info.filename.red().bold(), err_msg);
+                nr_errors += 1;
+                continue;
+            }
+        };
+        for err_msg in &migration.errors {
+            eprintln!("{}: ERROR {}", file_info.filename.red().bold(), err_msg);
+            nr_errors += 1;
+        }
+
+        let file_update = &migration.file_update;
+        if !file_update.has_changes() {
+            continue;
+        }
+        nr_changed_files += 1;
+
+        if dry_run {
+            print!("{}", file_update.unified_diff());
+        } else {
+            file_update.write()?;
+            println!(
+                "{}: migrated {} tag(s)",
+                file_update.filename.green().bold(),
+                migration.nr_migrated
+            );
+        }
+    }
+
+    if nr_errors > 0 {
+        return Err(format!("{} tag(s) could not be migrated", nr_errors));
+    }
+    if dry_run && nr_changed_files > 0 {
+        return Err(format!("{} file(s) would be changed", nr_changed_files));
+    }
+
+    Ok(())
+}
+
+/// Formats the pinned, newest in range and newest overall version of the
+/// tag, and whether a newer version exists outside of the requirement.
+fn format_outdated_info(
+    tag_version_result: &TagVersionResult,
+    policy: &policy::ResolvePolicy,
+) -> (String, bool) {
+    let tag = match tag_version_result {
+        Ok(tag_version) => &tag_version.tag,
+        Err(err_msg) => return (format!("  ERROR: {}", err_msg), false),
+    };
+
+    let in_range = tag_checker::TagChecker::get_current_version_from_source(tag, policy);
+    let newest = tag_checker::TagChecker::get_newest_version_from_source(tag, policy);
+    let is_out_of_range = match (&in_range, &newest) {
+        (Ok(in_range), Ok(newest)) => newest.get_version() > in_range.get_version(),
+        (Err(_), Ok(_)) => true,
+        _ => false,
+    };
+
+    let format_version = |result: &Result<version::FixedVersion, String>| match result {
+        Ok(v) => v.raw_version.clone(),
+        Err(err_msg) => format!("{} ({})", "not found".red(), err_msg),
+    };
+    let newest = if is_out_of_range {
+        format_version(&newest).yellow().bold().to_string()
+    } else {
+        format_version(&newest)
+    };
+    let line = format!(
+        "  {} {} [{}]: pinned {} ({} old), in range {}, newest {}",
+        tag.identifier,
+        tag.get_raw_version_req(),
+        tag.get_raw_source(),
+        tag.get_current_version(),
+        age::format_age(tag.get_age(policy.get_now())),
+        format_version(&in_range),
+        newest,
+    );
+    (line, is_out_of_range)
+}
+
+fn update(
+    files: Vec<String>,
+    policy: policy::ResolvePolicy,
+    options: tag_updater::UpdateOptions,
+    dry_run: bool,
+    consistent: bool,
+) -> Result<(), String> {
+    let scanner = tag_scanner::TagScanner::new(files);
+    let file_infos = scanner.do_scan();
+    let mut groups = tag_group::group_by_attribute(&file_infos);
+    if consistent {
+        groups.extend(tag_group::group_by_source(&file_infos));
+    }
+    let groups = tag_group::merge_groups(groups);
+    let updater = tag_updater::TagUpdater::new(policy, options).with_groups(&groups);
+
+    let mut nr_changed_files = 0;
+    let mut nr_errors = 0;
+
+    // files are updated concurrently, but reported and written in order
+    let file_updates: Vec<_> = file_infos
+        .par_iter()
+        .map(|file_info| updater.update_file(file_info))
+        .collect();
+
+    // a group is written either in all of its files or in none
+    let failed_files: BTreeSet<&str> = file_infos
+        .iter()
+        .zip(&file_updates)
+        .filter(|(_, file_update)| match file_update {
+            Ok(file_update) => file_update.tag_update_results.iter().any(Result::is_err),
+            Err(_) => true,
+        })
+        .map(|(file_info, _)| file_info.filename.as_str())
+        .collect();
+    let unknown_files: BTreeSet<&str> = file_infos
+        .iter()
+        .filter(|file_info| match &file_info.version_result {
+            Ok(file_version) => file_version.tag_version_results.iter().any(Result::is_err),
+            Err(_) => true,
+        })
+        .map(|file_info| file_info.filename.as_str())
+        .collect();
+    let held_back = tag_group::find_held_back_files(&groups, &failed_files, &unknown_files);
+    let mut nr_held_back = 0;
+    for (file_info, file_update) in file_infos.iter().zip(file_updates) {
+        let file_update = match file_update {
+            Ok(file_update) => file_update,
+            Err(err_msg) => {
+                eprintln!("{}: ERROR {}", file_info.filename.red().bold(), err_msg);
+                nr_errors += 1;
+                continue;
+            }
+        };
+
+        for tag_update_result in &file_update.tag_update_results {
+            if let Err(err_msg) = tag_update_result {
+                eprintln!("{}: ERROR {}", file_update.filename.red().bold(), err_msg);
+                nr_errors += 1;
+            }
+        }
+
+        for note in &file_update.notes {
+            println!("{}: {}", file_update.filename.yellow().bold(), note);
+        }
+        if !file_update.has_changes() {
+            continue;
+        }
+        if let Some(reason) = held_back.get(&file_update.filename) {
+            eprintln!(
+                "{}: ERROR not written, {}",
+                file_update.filename.red().bold(),
+                reason
+            );
+            nr_held_back += 1;
+            continue;
+        }
+        nr_changed_files += 1;
+
+        if !dry_run {
+            file_update.write()?;
+        }
+        println!("{}: ", file_update.filename.green().bold());
+        for tag_update in file_update.tag_update_results.iter().flatten().flatten() {
+            if tag_update.old_version_req != tag_update.new_version_req {
+                println!(
+                    "  {} requirement {} -> {}",
+                    tag_update.identifier, tag_update.old_version_req, tag_update.new_version_req
+                );
+            }
+            println!(
+                "  {} {} @ {} ({} old) -> {}",
+                tag_update.identifier,
+                tag_update.old_version,
+                tag_update.old_timestamp,
+                age::format_age(tag_update.old_age),
+                tag_update.new_version
+            );
+        }
+        if dry_run {
+            print!("{}", file_update.unified_diff());
+        }
+    }
+
+    let mut err_msgs = vec![];
+    if nr_errors > 0 {
+        err_msgs.push(format!("{} tag(s) could not be updated", nr_errors));
+    }
+    if nr_held_back > 0 {
+        err_msgs.push(format!(
+            "{} file(s) were not written to keep their groups consistent",
+            nr_held_back
+        ));
+    }
+    if !err_msgs.is_empty() {
+        return Err(err_msgs.join(", "));
+    }
+    if dry_run && nr_changed_files > 0 {
+        return Err(format!("{} file(s) would be changed", nr_changed_files));
+    }
+
     Ok(())
 }
 
-fn print_tag_version_info(tag_version_result: &TagVersionResult) {
+/// Formats a single line for the tag, and whether the tag is stale.
+fn format_tag_version_info(
+    tag_version_result: &TagVersionResult,
+    policy: &policy::ResolvePolicy,
+    max_age: Option<chrono::Duration>,
+) -> (String, bool) {
     match tag_version_result {
         Ok(tag_version) => {
-            let new_version = tag_checker::TagChecker::get_current_version_from_source(&tag_version.tag);
+            let new_version =
+                tag_checker::TagChecker::get_current_version_from_source(&tag_version.tag, policy);
             let new_version = match new_version {
-                Ok(v) => format!("{}", v.raw_version),
+                Ok(v) => v.raw_version,
                 Err(err_msg) => format!("{} ({})", "not found".to_owned().red(), err_msg),
             };
-            println!(
-                "  {} {} [{}] -> {}",
-                tag_version.tag.identifier.to_string(),
+            let tag_age = tag_version.tag.get_age(policy.get_now());
+            let is_stale = max_age.is_some_and(|max_age| tag_age > max_age);
+            let tag_age = if is_stale {
+                format!("{} old, STALE", age::format_age(tag_age))
+                    .red()
+                    .to_string()
+            } else {
+                format!("{} old", age::format_age(tag_age))
+            };
+            let line = format!(
+                "  {} {} [{}] -> {} ({})",
+                tag_version.tag.identifier,
                 tag_version.tag.get_raw_version_req(),
                 tag_version.tag.get_raw_source(),
                 new_version,
+                tag_age,
             );
+            (line, is_stale)
         }
-        Err(err_msg) => {
-            println!("  ERROR: {}", err_msg);
-        }
+        Err(err_msg) => (format!("  ERROR: {}", err_msg), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag_scanner::TagVersion;
+
+    fn get_tag(filename: &str, content: &str) -> TagVersionResult {
+        let tag = syntax::DemverTag::parse_all(filename, content)
+            .remove(0)
+            .unwrap();
+        Ok(TagVersion { tag })
+    }
+
+    fn get_policy_at(now: &str) -> policy::ResolvePolicy {
+        policy::ResolvePolicy::new(syntax::parse_timestamp(now).unwrap())
+    }
+
+    #[test]
+    fn tag_version_info_staleness() {
+        colored::control::set_override(false);
+        let tag = get_tag(
+            "./test/simple/default.env",
+            "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n",
+        );
+        let max_age = Some(chrono::Duration::days(30));
+
+        let (line, is_stale) =
+            format_tag_version_info(&tag, &get_policy_at("2020-12-15T18-18-09"), max_age);
+        assert_eq!(
+            line,
+            "  testapp ^1.0.0 [file(versions.ini)] -> 1.5.0 (10d old)"
+        );
+        assert!(!is_stale);
+
+        let (line, is_stale) =
+            format_tag_version_info(&tag, &get_policy_at("2021-02-03T18-18-09"), max_age);
+        assert_eq!(
+            line,
+            "  testapp ^1.0.0 [file(versions.ini)] -> 1.5.0 (60d old, STALE)"
+        );
+        assert!(is_stale);
+
+        let (_, is_stale) =
+            format_tag_version_info(&tag, &get_policy_at("2021-02-03T18-18-09"), None);
+        assert!(!is_stale);
+    }
+
+    #[test]
+    fn outdated_info() {
+        colored::control::set_override(false);
+        let policy = get_policy_at("2021-01-02T03-04-05");
+
+        let tag = get_tag(
+            "./test/simple/default.env",
+            "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n",
+        );
+        let (line, is_out_of_range) = format_outdated_info(&tag, &policy);
+        assert_eq!(
+            line,
+            "  testapp ^1.0.0 [file(versions.ini)]: pinned 1.0.0 (27d old), in range 1.5.0, newest 2.0.0"
+        );
+        assert!(is_out_of_range);
+
+        let tag = get_tag(
+            "./test/groups/worker.env",
+            "# [demver(>=1.0.0)|file(versions.ini)|app-worker] 1.0.0 @ 2020-12-05T18-18-09\n",
+        );
+        let (line, is_out_of_range) = format_outdated_info(&tag, &policy);
+        assert_eq!(
+            line,
+            "  app-worker >=1.0.0 [file(versions.ini)]: pinned 1.0.0 (27d old), in range 1.5.0, newest 1.5.0"
+        );
+        assert!(!is_out_of_range);
+
+        let tag = get_tag(
+            "./test/simple/default.env",
+            "# [demver(^1.0.0)|file(versions.ini)|unknown] 1.0.0 @ 2020-12-05T18-18-09\n",
+        );
+        let (line, is_out_of_range) = format_outdated_info(&tag, &policy);
+        assert!(line.contains("in range not found ("), "{}", line);
+        assert!(!is_out_of_range);
+
+        let (line, is_out_of_range) = format_outdated_info(&Err("bad tag".to_owned()), &policy);
+        assert_eq!(line, "  ERROR: bad tag");
+        assert!(!is_out_of_range);
+    }
+
+    #[test]
+    fn fallback_sources_agree() {
+        colored::control::set_override(false);
+        let policy = get_policy_at("2021-01-02T03-04-05");
+        let tag = get_tag(
+            "./test/multi/fallback.env",
+            "# [demver(^1.0.0)|file(outdated.ini),file(primary.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n",
+        );
+
+        let (line, _) = format_tag_version_info(&tag, &policy, None);
+        assert!(line.contains("-> 1.6.0"), "{}", line);
+        let (line, is_out_of_range) = format_outdated_info(&tag, &policy);
+        assert!(
+            line.contains("pinned 1.0.0 (27d old), in range 1.6.0, newest 1.6.0"),
+            "{}",
+            line
+        );
+        assert!(!is_out_of_range);
     }
 }
@@ -0,0 +1,92 @@
+use crate::syntax::TokenizedTag;
+use crate::tag_parser::TagSyntax;
+use crate::tag_scanner::FileInfo;
+use crate::tag_updater::FileUpdate;
+
+/// A file with all of its tags rewritten to the version 2 syntax.
+pub struct Migration {
+    pub file_update: FileUpdate,
+    pub nr_migrated: usize,
+    /// tags that could not be parsed and were left as they are
+    pub errors: Vec<String>,
+}
+
+pub fn migrate_file(file_info: &FileInfo) -> Result<Migration, String> {
+    let file_version = file_info.version_result.as_ref()?;
+    let mut updated = file_version.text_file.content.clone();
+    let mut errors = vec![];
+
+    let mut tags: Vec<&TokenizedTag> = vec![];
+    for tag_version_result in &file_version.tag_version_results {
+        match tag_version_result {
+            Ok(tag_version) => tags.push(tag_version.tag.get_tokenized_tag()),
+            Err(err_msg) => errors.push(err_msg.clone()),
+        }
+    }
+    let tags: Vec<&TokenizedTag> = tags
+        .into_iter()
+        .filter(|tag| tag.get_syntax() == TagSyntax::V1)
+        .collect();
+
+    for tag in tags.iter().rev() {
+        updated.replace_range(tag.get_idx_start()..tag.get_idx_end(), &tag.to_v2());
+    }
+
+    Ok(Migration {
+        file_update: FileUpdate {
+            filename: file_info.filename.clone(),
+            text_file: file_version.text_file.clone(),
+            updated,
+            tag_update_results: vec![],
+            notes: vec![],
+        },
+        nr_migrated: tags.len(),
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::DemverTag;
+    use crate::tag_scanner::TagScanner;
+
+    #[test]
+    fn migrate_to_v2() {
+        let file_infos = TagScanner::new(vec!["./test/migrate/default.env".to_owned()]).do_scan();
+
+        let migration = migrate_file(&file_infos[0]).unwrap();
+
+        assert_eq!(migration.nr_migrated, 2);
+        assert_eq!(migration.errors.len(), 0);
+        assert_eq!(
+            migration.file_update.updated,
+            "# [demver:2 req=^1.0.0 src=file(versions.ini) id=testapp] 1.0.0 @ 2020-12-05T18-18-09\n\
+             TEST_VERSION=sha256:1\n\
+             # [demver:2 req=\">=1.0.0, <2.0.0\" src=file(versions.ini) id=testapp prerelease=rc] 1.0.0 @ 2020-12-05T18-18-09\n\
+             OTHER_VERSION=sha256:1\n\
+             # [demver:2 req=^1.0.0 src=file(versions.ini) id=testapp future=\"kept as is\"] 1.0.0 @ 2020-12-05T18-18-09\n\
+             NEXT_VERSION=sha256:1\n"
+        );
+
+        // the migrated tags mean the same as the original ones
+        let parse = |content: &str| -> Vec<DemverTag> {
+            DemverTag::parse_all("default.env", content)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect()
+        };
+        let original = parse(&migration.file_update.text_file.content);
+        let migrated = parse(&migration.file_update.updated);
+        assert_eq!(migrated.len(), 3);
+        for (original, migrated) in original.iter().zip(&migrated) {
+            assert_eq!(
+                original.get_raw_version_req(),
+                migrated.get_raw_version_req()
+            );
+            assert_eq!(original.version_sources, migrated.version_sources);
+            assert_eq!(original.identifier, migrated.identifier);
+            assert_eq!(original.attributes, migrated.attributes);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use crate::scheme;
+use crate::syntax;
+use crate::version;
+use chrono::{DateTime, Duration, Utc};
+use std::fmt;
+
+/// Decides which pre-release versions may be picked. Without a policy the
+/// version requirement alone decides, as implemented by the semver crate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrereleasePolicy {
+    /// Never pick pre-releases.
+    Exclude,
+    /// Only pick pre-releases of the major.minor.patch the tag is pinned to.
+    SameRelease,
+    /// Pick pre-releases of the given channel, e.g. `rc` for `1.2.0-rc.1`.
+    Channel(String),
+}
+
+impl PrereleasePolicy {
+    pub fn parse(unparsed: &str) -> Result<Self, String> {
+        match unparsed {
+            "exclude" => Ok(PrereleasePolicy::Exclude),
+            "same-release" => Ok(PrereleasePolicy::SameRelease),
+            c if !c.is_empty() && c.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                Ok(PrereleasePolicy::Channel(c.to_owned()))
+            }
+            c => Err(format!(
+                "invalid pre-release policy '{}', expected exclude, same-release or a channel name",
+                c
+            )),
+        }
+    }
+
+    /// Decides for a pre-release given by the numbers of its release and its
+    /// pre-release identifiers, e.g. `rc.1`.
+    fn allows(&self, release: &[u64], pre: &str, pinned: Option<&scheme::Version>) -> bool {
+        match self {
+            PrereleasePolicy::Exclude => false,
+            PrereleasePolicy::SameRelease => pinned.is_some_and(|pinned| {
+                let pinned = release_parts(pinned);
+                let len = pinned.len().max(release.len());
+                (0..len).all(|idx| pinned.get(idx).unwrap_or(&0) == release.get(idx).unwrap_or(&0))
+            }),
+            PrereleasePolicy::Channel(channel) => pre
+                .split('.')
+                .next()
+                .and_then(|first| first.strip_prefix(channel.as_str()))
+                .is_some_and(|rest| rest.chars().all(|c| c.is_ascii_digit())),
+        }
+    }
+}
+
+impl fmt::Display for PrereleasePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrereleasePolicy::Exclude => write!(f, "exclude"),
+            PrereleasePolicy::SameRelease => write!(f, "same-release"),
+            PrereleasePolicy::Channel(channel) => write!(f, "{}", channel),
+        }
+    }
+}
+
+/// How far a pin may move away from the version it is pinned to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BumpLevel {
+    /// Keep major and minor, e.g. `1.2.0` to `1.2.3`.
+    Patch,
+    /// Keep major, e.g. `1.2.0` to `1.3.0`.
+    Minor,
+    /// Anything the requirement allows.
+    Major,
+}
+
+impl BumpLevel {
+    fn allows(&self, version: &scheme::Version, pinned: &scheme::Version) -> bool {
+        let nr_fixed_parts = match self {
+            BumpLevel::Patch => 2,
+            BumpLevel::Minor => 1,
+            BumpLevel::Major => return true,
+        };
+        let version = release_parts(version);
+        let pinned = release_parts(pinned);
+        (0..nr_fixed_parts)
+            .all(|idx| version.get(idx).unwrap_or(&0) == pinned.get(idx).unwrap_or(&0))
+    }
+}
+
+impl fmt::Display for BumpLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BumpLevel::Patch => write!(f, "patch"),
+            BumpLevel::Minor => write!(f, "minor"),
+            BumpLevel::Major => write!(f, "major"),
+        }
+    }
+}
+
+fn release_parts(version: &scheme::Version) -> Vec<u64> {
+    match version {
+        scheme::Version::Semver(v) => vec![v.major, v.minor, v.patch],
+        scheme::Version::Dotted(v) => v.parts.clone(),
+    }
+}
+
+/// Restrictions on which versions of a source may be picked, on top of the
+/// version requirement of a tag.
+#[derive(Debug, Clone)]
+pub struct ResolvePolicy {
+    now: DateTime<Utc>,
+    min_age: Option<Duration>,
+    prerelease: Option<PrereleasePolicy>,
+    bump: Option<BumpLevel>,
+    pinned: Option<scheme::Version>,
+}
+
+impl ResolvePolicy {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now,
+            min_age: None,
+            prerelease: None,
+            bump: None,
+            pinned: None,
+        }
+    }
+
+    /// Narrows the policy down to a single tag: the tag's own attributes
+    /// take precedence over the global settings.
+    pub fn for_tag(&self, tag: &syntax::DemverTag) -> Self {
+        let mut policy = self.for_attributes(&tag.attributes);
+        policy.pinned = Some(tag.get_current_version().clone());
+        policy
+    }
+
+    pub fn for_attributes(&self, attributes: &syntax::TagAttributes) -> Self {
+        let mut policy = self.clone();
+        if let Some(prerelease) = &attributes.prerelease {
+            policy.prerelease = Some(prerelease.clone());
+        }
+        policy
+    }
+
+    pub fn get_now(&self) -> &DateTime<Utc> {
+        &self.now
+    }
+
+    /// Only versions released at least `min_age` ago are allowed. Versions
+    /// without a release date are rejected, as their age can't be verified.
+    pub fn with_min_age(mut self, min_age: Option<Duration>) -> Self {
+        self.min_age = min_age;
+        self
+    }
+
+    pub fn get_min_age(&self) -> Option<Duration> {
+        self.min_age
+    }
+
+    pub fn with_prerelease(mut self, prerelease: Option<PrereleasePolicy>) -> Self {
+        self.prerelease = prerelease;
+        self
+    }
+
+    /// Caps how far a tag may move from its pinned version.
+    pub fn with_bump(mut self, bump: Option<BumpLevel>) -> Self {
+        self.bump = bump;
+        self
+    }
+
+    /// Checks the requirement and the policy. With a pre-release policy, a
+    /// pre-release satisfies the requirement if its release would.
+    pub fn matches(
+        &self,
+        requested_version: &scheme::VersionReq,
+        version: &version::FixedVersion,
+    ) -> bool {
+        let matches_req = match (&self.prerelease, version.get_version()) {
+            (Some(prerelease), scheme::Version::Semver(semver)) if semver.is_prerelease() => {
+                let release = semver::Version::new(semver.major, semver.minor, semver.patch);
+                let pre: Vec<String> = semver.pre.iter().map(|i| i.to_string()).collect();
+                prerelease.allows(
+                    &[semver.major, semver.minor, semver.patch],
+                    &pre.join("."),
+                    self.pinned.as_ref(),
+                ) && requested_version.matches(&scheme::Version::Semver(release))
+            }
+            (Some(prerelease), scheme::Version::Dotted(dotted))
+                if dotted.get_prerelease().is_some() =>
+            {
+                prerelease.allows(&dotted.parts, &dotted.suffix, self.pinned.as_ref())
+                    && requested_version.matches(&scheme::Version::Dotted(dotted.get_release()))
+            }
+            _ => requested_version.matches(version.get_version()),
+        };
+
+        matches_req && self.allows(version)
+    }
+
+    /// The pinned version of a tag is always allowed, keeping it never
+    /// violates the policy.
+    pub fn allows(&self, version: &version::FixedVersion) -> bool {
+        if self.pinned.as_ref() == Some(version.get_version()) {
+            return true;
+        }
+        if let Some(min_age) = self.min_age {
+            match version.released {
+                Some(released) if self.now - released >= min_age => {}
+                _ => return false,
+            }
+        }
+        if let (Some(bump), Some(pinned)) = (self.bump, &self.pinned) {
+            if !bump.allows(version.get_version(), pinned) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl fmt::Display for ResolvePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut restrictions: Vec<String> = vec![];
+        if let Some(min_age) = self.min_age {
+            restrictions.push(format!("minimum age {}", crate::age::format_age(min_age)));
+        }
+        if let Some(prerelease) = &self.prerelease {
+            restrictions.push(format!("pre-releases {}", prerelease));
+        }
+        if let Some(bump) = self.bump {
+            restrictions.push(format!("{} bumps only", bump));
+        }
+
+        match restrictions.len() {
+            0 => write!(f, "no restrictions"),
+            _ => write!(f, "{}", restrictions.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn version_released(released: Option<DateTime<Utc>>) -> version::FixedVersion {
+        version::FixedVersion {
+            raw_version: "1.0.0".to_owned(),
+            version: scheme::VersionScheme::Semver
+                .parse_version("1.0.0")
+                .unwrap(),
+            hash: "1".to_owned(),
+            released,
+        }
+    }
+
+    fn version(raw_version: &str) -> version::FixedVersion {
+        version::FixedVersion {
+            raw_version: raw_version.to_owned(),
+            version: scheme::VersionScheme::Semver
+                .parse_version(raw_version)
+                .unwrap(),
+            hash: "1".to_owned(),
+            released: None,
+        }
+    }
+
+    #[test]
+    fn min_age() {
+        let now = Utc.with_ymd_and_hms(2021, 1, 10, 0, 0, 0).unwrap();
+        let sut = ResolvePolicy::new(now).with_min_age(Some(Duration::days(7)));
+
+        assert!(sut.allows(&version_released(Some(
+            Utc.with_ymd_and_hms(2021, 1, 3, 0, 0, 0).unwrap()
+        ))));
+        assert!(!sut.allows(&version_released(Some(
+            Utc.with_ymd_and_hms(2021, 1, 3, 0, 0, 1).unwrap()
+        ))));
+        assert!(!sut.allows(&version_released(None)));
+    }
+
+    #[test]
+    fn min_age_keeps_pinned() {
+        let mut sut = ResolvePolicy::new(Utc::now()).with_min_age(Some(Duration::days(7)));
+        sut.pinned = Some(version("1.0.0").version);
+
+        assert!(sut.allows(&version_released(None)));
+        assert!(!sut.allows(&version("1.5.0")));
+    }
+
+    #[test]
+    fn no_restrictions() {
+        let sut = ResolvePolicy::new(Utc::now());
+
+        assert!(sut.allows(&version_released(None)));
+    }
+
+    #[test]
+    fn prerelease_default() {
+        let req = scheme::VersionScheme::Semver.parse_req("^1.0.0").unwrap();
+        let sut = ResolvePolicy::new(Utc::now());
+
+        assert!(sut.matches(&req, &version("1.5.0")));
+        assert!(!sut.matches(&req, &version("1.5.0-rc.1")));
+    }
+
+    #[test]
+    fn prerelease_exclude() {
+        let req = scheme::VersionScheme::Semver
+            .parse_req(">=1.0.0-rc.1")
+            .unwrap();
+        let sut = ResolvePolicy::new(Utc::now()).with_prerelease(Some(PrereleasePolicy::Exclude));
+
+        assert!(sut.matches(&req, &version("1.0.0")));
+        assert!(!sut.matches(&req, &version("1.0.0-rc.2")));
+    }
+
+    #[test]
+    fn prerelease_same_release() {
+        let req = scheme::VersionScheme::Semver.parse_req("^1.0.0").unwrap();
+        let mut sut =
+            ResolvePolicy::new(Utc::now()).with_prerelease(Some(PrereleasePolicy::SameRelease));
+        sut.pinned = Some(
+            scheme::VersionScheme::Semver
+                .parse_version("1.2.0-rc.1")
+                .unwrap(),
+        );
+
+        assert!(sut.matches(&req, &version("1.2.0-rc.2")));
+        assert!(!sut.matches(&req, &version("1.3.0-rc.1")));
+    }
+
+    #[test]
+    fn prerelease_channel() {
+        let req = scheme::VersionScheme::Semver.parse_req("^1.0.0").unwrap();
+        let sut = ResolvePolicy::new(Utc::now())
+            .with_prerelease(Some(PrereleasePolicy::parse("rc").unwrap()));
+
+        assert!(sut.matches(&req, &version("1.5.0-rc.1")));
+        assert!(sut.matches(&req, &version("1.5.0-rc2")));
+        assert!(!sut.matches(&req, &version("1.5.0-beta.1")));
+        assert!(!sut.matches(&req, &version("1.5.0-rcx")));
+        assert!(!sut.matches(&req, &version("2.0.0-rc.1")));
+    }
+
+    #[test]
+    fn prerelease_dotted() {
+        let req = scheme::VersionScheme::Dotted.parse_req("^1.21").unwrap();
+        let dotted = |raw_version: &str| version::FixedVersion {
+            raw_version: raw_version.to_owned(),
+            version: scheme::VersionScheme::Dotted
+                .parse_version(raw_version)
+                .unwrap(),
+            hash: "1".to_owned(),
+            released: None,
+        };
+
+        let sut = ResolvePolicy::new(Utc::now());
+        assert!(sut.matches(&req, &dotted("1.22")));
+        assert!(!sut.matches(&req, &dotted("1.22-rc1")));
+
+        let sut = sut.with_prerelease(Some(PrereleasePolicy::Exclude));
+        assert!(!sut.matches(&req, &dotted("1.22-rc1")));
+
+        let sut = sut.with_prerelease(Some(PrereleasePolicy::parse("rc").unwrap()));
+        assert!(sut.matches(&req, &dotted("1.22-rc1")));
+        assert!(sut.matches(&req, &dotted("1.22-rc.2")));
+        assert!(!sut.matches(&req, &dotted("1.22-beta1")));
+        assert!(!sut.matches(&req, &dotted("2.0-rc1")));
+
+        let mut sut = sut.with_prerelease(Some(PrereleasePolicy::SameRelease));
+        sut.pinned = Some(
+            scheme::VersionScheme::Dotted
+                .parse_version("1.22-rc1")
+                .unwrap(),
+        );
+        assert!(sut.matches(&req, &dotted("1.22.0-rc2")));
+        assert!(!sut.matches(&req, &dotted("1.23-rc1")));
+    }
+
+    #[test]
+    fn bump_level() {
+        let req = scheme::VersionScheme::Semver.parse_req(">=1.0.0").unwrap();
+        let mut sut = ResolvePolicy::new(Utc::now()).with_bump(Some(BumpLevel::Minor));
+        sut.pinned = Some(
+            scheme::VersionScheme::Semver
+                .parse_version("1.2.0")
+                .unwrap(),
+        );
+
+        assert!(sut.matches(&req, &version("1.9.0")));
+        assert!(!sut.matches(&req, &version("2.0.0")));
+
+        let sut = sut.with_bump(Some(BumpLevel::Patch));
+        assert!(sut.matches(&req, &version("1.2.5")));
+        assert!(!sut.matches(&req, &version("1.3.0")));
+    }
+
+    #[test]
+    fn prerelease_parse() {
+        assert_eq!(
+            PrereleasePolicy::parse("exclude").unwrap(),
+            PrereleasePolicy::Exclude
+        );
+        assert_eq!(
+            PrereleasePolicy::parse("same-release").unwrap(),
+            PrereleasePolicy::SameRelease
+        );
+        assert!(PrereleasePolicy::parse("").is_err());
+        assert!(PrereleasePolicy::parse("r-c").is_err());
+    }
+}
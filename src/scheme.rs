@@ -0,0 +1,647 @@
+use regex::Regex;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// How the versions of a tag are written, compared and requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum VersionScheme {
+    /// Strict semantic versioning, e.g. `1.2.3-rc.1`.
+    #[default]
+    Semver,
+    /// Semantic versioning that tolerates a `v` prefix and missing minor or
+    /// patch numbers, e.g. `v1.2`.
+    Loose,
+    /// Calendar versioning, e.g. `2024.05.01`.
+    Calver,
+    /// Any number of dot separated numbers, e.g. `1.2.3.4`.
+    Dotted,
+}
+
+impl VersionScheme {
+    pub fn parse(unparsed: &str) -> Result<Self, String> {
+        match unparsed {
+            "semver" => Ok(VersionScheme::Semver),
+            "loose" => Ok(VersionScheme::Loose),
+            "calver" => Ok(VersionScheme::Calver),
+            "dotted" => Ok(VersionScheme::Dotted),
+            s => Err(format!(
+                "unknown version scheme '{}', expected semver, loose, calver or dotted",
+                s
+            )),
+        }
+    }
+
+    pub fn parse_version(&self, unparsed: &str) -> Result<Version, String> {
+        match self {
+            VersionScheme::Semver => semver::Version::parse(unparsed)
+                .map(Version::Semver)
+                .map_err(|e| format!("Failed to parse semver: {}", e)),
+            VersionScheme::Loose => semver::Version::parse(&coerce_loose(unparsed)?)
+                .map(Version::Semver)
+                .map_err(|e| format!("Failed to parse loose semver '{}': {}", unparsed, e)),
+            VersionScheme::Calver => {
+                let version = DottedVersion::parse(unparsed)?;
+                validate_calver(&version.parts)
+                    .map_err(|e| format!("Failed to parse calver '{}': {}", unparsed, e))?;
+                Ok(Version::Dotted(version))
+            }
+            VersionScheme::Dotted => DottedVersion::parse(unparsed).map(Version::Dotted),
+        }
+    }
+
+    pub fn parse_req(&self, unparsed: &str) -> Result<VersionReq, String> {
+        let matcher = match self {
+            _ if unparsed.starts_with('@') => Matcher::Channel(parse_channel(&unparsed[1..])?),
+            VersionScheme::Semver => semver::VersionReq::parse(unparsed)
+                .map(Matcher::Semver)
+                .map_err(|e| format!("Failed to parse semver: {}", e))?,
+            VersionScheme::Loose => {
+                lazy_static! {
+                    static ref RE: Regex = Regex::new(r"(^|[^\w])[vV](\d)").unwrap();
+                }
+                semver::VersionReq::parse(&RE.replace_all(unparsed, "$1$2"))
+                    .map(Matcher::Semver)
+                    .map_err(|e| format!("Failed to parse loose semver requirement: {}", e))?
+            }
+            VersionScheme::Calver | VersionScheme::Dotted => {
+                Matcher::Dotted(DottedComparator::parse_all(unparsed)?)
+            }
+        };
+
+        Ok(VersionReq {
+            format: VersionFormat {
+                scheme: *self,
+                variant: None,
+            },
+            matcher,
+        })
+    }
+}
+
+impl fmt::Display for VersionScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionScheme::Semver => write!(f, "semver"),
+            VersionScheme::Loose => write!(f, "loose"),
+            VersionScheme::Calver => write!(f, "calver"),
+            VersionScheme::Dotted => write!(f, "dotted"),
+        }
+    }
+}
+
+/// The scheme of a tag's versions, plus the image flavor the versions must be
+/// of, e.g. `alpine` for `1.21-alpine`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct VersionFormat {
+    pub scheme: VersionScheme,
+    pub variant: Option<String>,
+}
+
+impl VersionFormat {
+    /// Splits the variant off and parses the remaining base version in the
+    /// scheme. Versions of another variant are rejected.
+    pub fn parse_version(&self, unparsed: &str) -> Result<Version, String> {
+        let base = match &self.variant {
+            Some(variant) => unparsed
+                .strip_suffix(variant.as_str())
+                .and_then(|base| base.strip_suffix('-'))
+                .ok_or_else(|| format!("'{}' is not of variant {}", unparsed, variant))?,
+            None => unparsed,
+        };
+
+        let version = self.scheme.parse_version(base)?;
+        let suffix = match &version {
+            Version::Semver(v) => {
+                let pre: Vec<String> = v.pre.iter().map(|i| i.to_string()).collect();
+                pre.join(".")
+            }
+            Version::Dotted(v) => v.suffix.clone(),
+        };
+        if !suffix.is_empty() && split_prerelease(&suffix).is_none() {
+            return Err(format!("'{}' is of variant {}", unparsed, suffix));
+        }
+        Ok(version)
+    }
+
+    pub fn parse_req(&self, unparsed: &str) -> Result<VersionReq, String> {
+        let mut version_req = self.scheme.parse_req(unparsed)?;
+        version_req.format = self.clone();
+        Ok(version_req)
+    }
+}
+
+fn parse_channel(unparsed: &str) -> Result<String, String> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_';
+    match unparsed {
+        c if !c.is_empty() && c.chars().all(is_valid) => Ok(c.to_owned()),
+        c => Err(format!("invalid channel name '{}'", c)),
+    }
+}
+
+/// Turns `v1.2-rc.1` into `1.2.0-rc.1`.
+fn coerce_loose(unparsed: &str) -> Result<String, String> {
+    let unparsed = unparsed.trim_start_matches(['v', 'V']);
+    let core_end = unparsed.find(['-', '+']).unwrap_or(unparsed.len());
+    let (core, rest) = unparsed.split_at(core_end);
+
+    let mut parts: Vec<u64> = vec![];
+    for part in core.split('.') {
+        parts.push(
+            part.parse()
+                .map_err(|_| format!("'{}' is not a loose semantic version", unparsed))?,
+        );
+    }
+    if parts.len() > 3 {
+        return Err(format!("'{}' has more than three numbers", unparsed));
+    }
+    parts.resize(3, 0);
+
+    Ok(format!("{}.{}.{}{}", parts[0], parts[1], parts[2], rest))
+}
+
+fn validate_calver(parts: &[u64]) -> Result<(), String> {
+    if parts.len() < 2 {
+        return Err("expected at least year and month".to_owned());
+    }
+    if !(parts[0] < 100 || (1970..10000).contains(&parts[0])) {
+        return Err(format!("{} is not a year", parts[0]));
+    }
+    if !(1..=12).contains(&parts[1]) {
+        return Err(format!("{} is not a month", parts[1]));
+    }
+    Ok(())
+}
+
+/// A version in one of the supported schemes.
+#[derive(Debug, Clone)]
+pub enum Version {
+    Semver(semver::Version),
+    Dotted(DottedVersion),
+}
+
+impl Version {
+    /// The caret requirement of the release line this version belongs to,
+    /// e.g. `^2.0.0` for `2.3.1` or `^0.4.0` for `0.4.2`.
+    pub fn get_caret_req(&self) -> String {
+        match self {
+            Version::Semver(v) if v.major > 0 => format!("^{}.0.0", v.major),
+            Version::Semver(v) if v.minor > 0 => format!("^0.{}.0", v.minor),
+            Version::Semver(v) => format!("^0.0.{}", v.patch),
+            Version::Dotted(v) => format!("^{}", v.parts[0]),
+        }
+    }
+}
+
+/// Semver ignores build metadata for ordering, so versions only differing in
+/// it are ordered by their build identifiers to keep the choice between
+/// them deterministic.
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Version::Semver(a), Version::Semver(b)) => {
+                a.cmp(b).then_with(|| a.build.cmp(&b.build))
+            }
+            (Version::Dotted(a), Version::Dotted(b)) => a.cmp(b),
+            (Version::Semver(_), Version::Dotted(_)) => Ordering::Less,
+            (Version::Dotted(_), Version::Semver(_)) => Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Version::Semver(v) => write!(f, "{}", v),
+            Version::Dotted(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// A suffix of letters and a number, like `rc1` or `beta.2`, marks a
+/// pre-release and is split into its name and number. Other suffixes, like
+/// `alpine` or `alpine3.18`, are variants.
+fn split_prerelease(suffix: &str) -> Option<(&str, u64)> {
+    let rest = suffix.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let name = &suffix[..suffix.len() - rest.len()];
+    let number = rest.strip_prefix('.').unwrap_or(rest);
+    if name.is_empty() || number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, number.parse().ok()?))
+}
+
+/// Dot separated numbers with an optional `-suffix`, e.g. `1.2.3.4-alpine`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DottedVersion {
+    pub parts: Vec<u64>,
+    pub suffix: String,
+}
+
+impl DottedVersion {
+    pub fn parse(unparsed: &str) -> Result<Self, String> {
+        let (core, suffix) = match unparsed.split_once('-') {
+            Some((core, suffix)) => (core, suffix),
+            None => (unparsed, ""),
+        };
+
+        let mut parts: Vec<u64> = vec![];
+        for part in core.split('.') {
+            parts.push(
+                part.parse()
+                    .map_err(|_| format!("'{}' is not a dotted version", unparsed))?,
+            );
+        }
+
+        Ok(Self {
+            parts,
+            suffix: suffix.to_owned(),
+        })
+    }
+
+    fn part(&self, idx: usize) -> u64 {
+        self.parts.get(idx).copied().unwrap_or(0)
+    }
+
+    /// The name and number of a pre-release suffix, see `split_prerelease`.
+    pub fn get_prerelease(&self) -> Option<(&str, u64)> {
+        split_prerelease(&self.suffix)
+    }
+
+    /// The version without its suffix.
+    pub fn get_release(&self) -> Self {
+        Self {
+            parts: self.parts.clone(),
+            suffix: String::new(),
+        }
+    }
+
+    /// Compares the numbers only, missing numbers count as zero.
+    fn cmp_parts(&self, other: &[u64]) -> Ordering {
+        let len = self.parts.len().max(other.len());
+        (0..len)
+            .map(|idx| self.part(idx).cmp(other.get(idx).unwrap_or(&0)))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl Ord for DottedVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_parts(&other.parts)
+            .then_with(|| self.parts.len().cmp(&other.parts.len()))
+            .then_with(|| match (self.get_prerelease(), other.get_prerelease()) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                // pre-releases come before their release
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => self.suffix.cmp(&other.suffix),
+            })
+    }
+}
+
+impl PartialOrd for DottedVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for DottedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.parts.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", parts.join("."))?;
+        if !self.suffix.is_empty() {
+            write!(f, "-{}", self.suffix)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DottedOp {
+    Exact,
+    Prefix,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Caret,
+    Tilde,
+}
+
+/// A single comparison of a dotted requirement, e.g. `>=2024.05`.
+#[derive(Debug, Clone)]
+struct DottedComparator {
+    op: DottedOp,
+    parts: Vec<u64>,
+}
+
+impl DottedComparator {
+    /// Parses comma separated comparators. A bare version or one ending in
+    /// `.*` matches every version starting with it, `=` is an exact match,
+    /// `^` keeps the first number and `~` all but the last given number.
+    fn parse_all(unparsed: &str) -> Result<Vec<Self>, String> {
+        unparsed.split(',').map(|c| Self::parse(c.trim())).collect()
+    }
+
+    fn parse(unparsed: &str) -> Result<Self, String> {
+        let ops = [
+            (">=", DottedOp::GreaterEq),
+            ("<=", DottedOp::LessEq),
+            (">", DottedOp::Greater),
+            ("<", DottedOp::Less),
+            ("=", DottedOp::Exact),
+            ("^", DottedOp::Caret),
+            ("~", DottedOp::Tilde),
+        ];
+        let (op, rest) = ops
+            .iter()
+            .find_map(|(prefix, op)| unparsed.strip_prefix(prefix).map(|rest| (*op, rest)))
+            .unwrap_or((DottedOp::Prefix, unparsed));
+        let rest = rest.trim();
+
+        let mut parts: Vec<u64> = vec![];
+        let mut has_wildcard = false;
+        for part in rest.split('.') {
+            if has_wildcard {
+                return Err(format!(
+                    "wildcard must be last in requirement '{}'",
+                    unparsed
+                ));
+            }
+            match part {
+                "*" | "x" | "X" => has_wildcard = true,
+                p => parts.push(
+                    p.parse()
+                        .map_err(|_| format!("invalid requirement '{}'", unparsed))?,
+                ),
+            }
+        }
+        if has_wildcard && op != DottedOp::Prefix {
+            return Err(format!(
+                "wildcards are only allowed without operator in '{}'",
+                unparsed
+            ));
+        }
+
+        Ok(Self { op, parts })
+    }
+
+    fn matches(&self, version: &DottedVersion) -> bool {
+        let has_prefix = |len: usize| (0..len).all(|idx| version.part(idx) == self.parts[idx]);
+        let ordering = version.cmp_parts(&self.parts);
+
+        match self.op {
+            DottedOp::Exact => ordering == Ordering::Equal,
+            DottedOp::Prefix => has_prefix(self.parts.len()),
+            DottedOp::Greater => ordering == Ordering::Greater,
+            DottedOp::GreaterEq => ordering != Ordering::Less,
+            DottedOp::Less => ordering == Ordering::Less,
+            DottedOp::LessEq => ordering != Ordering::Greater,
+            DottedOp::Caret => ordering != Ordering::Less && has_prefix(1.min(self.parts.len())),
+            DottedOp::Tilde => {
+                ordering != Ordering::Less && has_prefix(self.parts.len().saturating_sub(1).max(1))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Semver(semver::VersionReq),
+    Dotted(Vec<DottedComparator>),
+    /// `@stable`: whatever version the source's channel points to.
+    Channel(String),
+}
+
+/// A version requirement in the grammar of its scheme.
+#[derive(Debug, Clone)]
+pub struct VersionReq {
+    format: VersionFormat,
+    matcher: Matcher,
+}
+
+impl VersionReq {
+    pub fn get_format(&self) -> &VersionFormat {
+        &self.format
+    }
+
+    pub fn get_channel(&self) -> Option<&str> {
+        match &self.matcher {
+            Matcher::Channel(channel) => Some(channel),
+            _ => None,
+        }
+    }
+
+    /// A channel requirement never matches on its own, it has to be resolved
+    /// through the source.
+    pub fn matches(&self, version: &Version) -> bool {
+        match (&self.matcher, version) {
+            (Matcher::Semver(req), Version::Semver(v)) => req.matches(v),
+            (Matcher::Dotted(comparators), Version::Dotted(v)) => {
+                v.get_prerelease().is_none() && comparators.iter().all(|c| c.matches(v))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(scheme: VersionScheme, req: &str, version: &str) -> bool {
+        let req = scheme.parse_req(req).unwrap();
+        req.matches(&scheme.parse_version(version).unwrap())
+    }
+
+    #[test]
+    fn loose_coercion() {
+        let sut = VersionScheme::Loose;
+
+        assert_eq!(sut.parse_version("v1.2").unwrap().to_string(), "1.2.0");
+        assert_eq!(sut.parse_version("3").unwrap().to_string(), "3.0.0");
+        assert_eq!(
+            sut.parse_version("1.2-rc.1").unwrap().to_string(),
+            "1.2.0-rc.1"
+        );
+        assert!(sut.parse_version("1.2.3.4").is_err());
+        assert!(matches(sut, "^v1.2", "v1.5"));
+        assert!(!matches(sut, "^v1.2", "v2.0"));
+    }
+
+    #[test]
+    fn calver() {
+        let sut = VersionScheme::Calver;
+
+        assert!(sut.parse_version("2024.05.01").is_ok());
+        assert!(sut.parse_version("24.05").is_ok());
+        assert!(sut.parse_version("2024.13.01").is_err());
+        assert!(sut.parse_version("2024").is_err());
+        assert!(matches(sut, "2024.05", "2024.05.31"));
+        assert!(!matches(sut, "2024.05", "2024.06.01"));
+        assert!(matches(sut, ">=2024.05, <2025", "2024.12.01"));
+        assert!(!matches(sut, ">=2024.05, <2025", "2025.01.01"));
+    }
+
+    #[test]
+    fn dotted_order() {
+        let sut = VersionScheme::Dotted;
+        let mut versions: Vec<Version> = ["1.2.3.10", "1.2.3.9", "1.2", "1.2.0.0", "1.10"]
+            .iter()
+            .map(|v| sut.parse_version(v).unwrap())
+            .collect();
+        versions.sort();
+
+        let sorted: Vec<String> = versions.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            sorted,
+            vec!["1.2", "1.2.0.0", "1.2.3.9", "1.2.3.10", "1.10"]
+        );
+    }
+
+    #[test]
+    fn dotted_requirements() {
+        let sut = VersionScheme::Dotted;
+
+        assert!(matches(sut, "=1.2", "1.2.0.0"));
+        assert!(!matches(sut, "=1.2", "1.2.0.1"));
+        assert!(matches(sut, "1.2.*", "1.2.7.1"));
+        assert!(matches(sut, "^1.2.3.4", "1.9"));
+        assert!(!matches(sut, "^1.2.3.4", "1.2.3.3"));
+        assert!(!matches(sut, "^1.2.3.4", "2.0"));
+        assert!(matches(sut, "~1.2.3.4", "1.2.3.9"));
+        assert!(!matches(sut, "~1.2.3.4", "1.2.4.0"));
+        assert!(matches(sut, "*", "7"));
+        assert!(sut.parse_req(">=1.*").is_err());
+        assert!(sut.parse_req("1.a").is_err());
+    }
+
+    #[test]
+    fn dotted_suffix() {
+        let sut = VersionScheme::Dotted;
+        let version = sut.parse_version("1.21-alpine").unwrap();
+
+        assert_eq!(version.to_string(), "1.21-alpine");
+        assert!(matches(sut, "^1.20", "1.21-alpine"));
+    }
+
+    #[test]
+    fn dotted_prerelease() {
+        let sut = VersionScheme::Dotted;
+        let parse = |v| sut.parse_version(v).unwrap();
+
+        assert!(parse("1.22-rc1") < parse("1.22"));
+        assert!(parse("1.22-rc.2") < parse("1.22-rc.10"));
+        assert!(parse("1.21") < parse("1.22-rc1"));
+        assert!(!matches(sut, "^1.20", "1.22-rc1"));
+        let format = VersionFormat {
+            scheme: sut,
+            variant: None,
+        };
+        assert!(format.parse_version("1.22-rc1").is_ok());
+        assert!(format.parse_version("1.22-alpine").is_err());
+    }
+
+    #[test]
+    fn semver_prerelease_or_variant() {
+        let sut = VersionFormat {
+            scheme: VersionScheme::Semver,
+            variant: None,
+        };
+
+        assert!(sut.parse_version("1.21.3").is_ok());
+        assert!(sut.parse_version("1.21.3-rc1").is_ok());
+        assert!(sut.parse_version("1.21.3-rc.1").is_ok());
+        assert!(sut.parse_version("1.21.3-alpine").is_err());
+        assert!(sut.parse_version("1.21.3-alpine3.18").is_err());
+        assert!(sut.parse_version("1.21.3-bookworm-slim").is_err());
+    }
+
+    #[test]
+    fn variant_split() {
+        let sut = VersionFormat {
+            scheme: VersionScheme::Dotted,
+            variant: Some("bookworm-slim".to_owned()),
+        };
+
+        assert_eq!(
+            sut.parse_version("1.21-bookworm-slim").unwrap().to_string(),
+            "1.21"
+        );
+        assert!(sut.parse_version("1.21").is_err());
+        assert!(sut.parse_version("1.21-alpine").is_err());
+        assert!(sut.parse_version("1.21-trixie-bookworm-slim").is_err());
+    }
+
+    #[test]
+    fn variant_semver() {
+        let sut = VersionFormat {
+            scheme: VersionScheme::Semver,
+            variant: Some("alpine".to_owned()),
+        };
+
+        assert_eq!(
+            sut.parse_version("1.2.0-rc.1-alpine").unwrap().to_string(),
+            "1.2.0-rc.1"
+        );
+        assert!(sut
+            .parse_req("^1.2")
+            .unwrap()
+            .matches(&sut.parse_version("1.5.0-alpine").unwrap()));
+    }
+
+    #[test]
+    fn variant_none() {
+        let sut = VersionFormat {
+            scheme: VersionScheme::Dotted,
+            variant: None,
+        };
+
+        assert!(sut.parse_version("1.21").is_ok());
+        assert!(sut.parse_version("1.21-alpine").is_err());
+    }
+
+    #[test]
+    fn caret_req() {
+        let caret_req = |scheme: VersionScheme, v| scheme.parse_version(v).unwrap().get_caret_req();
+
+        assert_eq!(caret_req(VersionScheme::Semver, "2.3.1"), "^2.0.0");
+        assert_eq!(caret_req(VersionScheme::Semver, "0.4.2"), "^0.4.0");
+        assert_eq!(caret_req(VersionScheme::Semver, "0.0.3"), "^0.0.3");
+        assert_eq!(caret_req(VersionScheme::Calver, "2025.01.05"), "^2025");
+    }
+
+    #[test]
+    fn channel_req() {
+        let sut = VersionScheme::Dotted.parse_req("@stable").unwrap();
+
+        assert_eq!(sut.get_channel(), Some("stable"));
+        assert!(!sut.matches(&VersionScheme::Dotted.parse_version("1.0").unwrap()));
+        assert!(VersionScheme::Semver.parse_req("@").is_err());
+        assert!(VersionScheme::Semver.parse_req("@st able").is_err());
+    }
+
+    #[test]
+    fn semver_build_metadata_order() {
+        let sut = VersionScheme::Semver;
+        let a = sut.parse_version("1.0.0+build.2").unwrap();
+        let b = sut.parse_version("1.0.0+build.10").unwrap();
+
+        assert!(a < b);
+        assert_ne!(a, b);
+    }
+}
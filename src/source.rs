@@ -1,11 +1,473 @@
+use crate::policy::ResolvePolicy;
+use crate::scheme;
 use crate::version;
 
 pub type FixedVersionResult = Result<version::FixedVersion, String>;
 
-pub trait VersionSource {
+/// Tags are resolved concurrently, so sources are used across threads.
+pub trait VersionSource: Send + Sync {
+    /// Returns every version the source knows for the identifier, in no
+    /// particular order. Versions not valid in the format are left out.
+    fn get_versions(
+        &self,
+        identifier: Option<&str>,
+        format: &scheme::VersionFormat,
+    ) -> Result<Vec<version::FixedVersion>, String>;
+
+    /// Returns the raw version a channel like `stable` currently points to.
+    fn get_channel(&self, _identifier: Option<&str>, channel: &str) -> Result<String, String> {
+        Err(format!(
+            "source does not support channels like @{}",
+            channel
+        ))
+    }
+
+    /// Returns the identifiers the source knows about, e.g. for completion.
+    fn get_identifiers(&self) -> Result<Vec<String>, String> {
+        Err("source can't list its identifiers".to_owned())
+    }
+
+    /// Identifies a remote source in the on-disk cache. Local sources are
+    /// read directly and are never cached.
+    fn get_cache_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Called with the version that was picked, before it is returned.
+    fn verify_version(
+        &self,
+        _identifier: Option<&str>,
+        _format: &scheme::VersionFormat,
+        _version: &version::FixedVersion,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Returns the newest version matching the request that the policy allows.
     fn get_fixed_version(
         &self,
-        requested_version: &semver::VersionReq,
+        requested_version: &scheme::VersionReq,
+        identifier: Option<&str>,
+        policy: &ResolvePolicy,
+    ) -> FixedVersionResult {
+        resolve_fixed_version(self, requested_version, identifier, policy)
+    }
+}
+
+/// The default implementation of `get_fixed_version`, for sources that
+/// override it but still resolve this way in some cases.
+pub fn resolve_fixed_version<S: VersionSource + ?Sized>(
+    source: &S,
+    requested_version: &scheme::VersionReq,
+    identifier: Option<&str>,
+    policy: &ResolvePolicy,
+) -> FixedVersionResult {
+    let format = requested_version.get_format();
+    let versions = source.get_versions(identifier, format)?;
+
+    let version = match requested_version.get_channel() {
+        Some(channel) => {
+            let raw_version = source.get_channel(identifier, channel)?;
+            let target = format.parse_version(&raw_version)?;
+            let version = versions
+                .into_iter()
+                .find(|v| v.get_version() == &target)
+                .ok_or_else(|| {
+                    format!(
+                        "channel @{} points to unknown version {}",
+                        channel, raw_version
+                    )
+                })?;
+            if !policy.allows(&version) {
+                return Err(format!(
+                    "channel @{} points to {}, which is not allowed by the policy ({})",
+                    channel, raw_version, policy
+                ));
+            }
+            version
+        }
+        None => {
+            let nr_matching = versions
+                .iter()
+                .filter(|v| requested_version.matches(v.get_version()))
+                .count();
+
+            let best_match = versions
+                .into_iter()
+                .filter(|v| policy.matches(requested_version, v))
+                .max();
+
+            match best_match {
+                Some(m) => m,
+                None if nr_matching > 0 => {
+                    return Err(format!(
+                        "{} matching version(s) found, but none is allowed by the policy ({})",
+                        nr_matching, policy
+                    ))
+                }
+                None => return Err("no match found".to_owned()),
+            }
+        }
+    };
+
+    source.verify_version(identifier, format, &version)?;
+    Ok(version)
+}
+
+/// How a tag with more than one source resolves its versions.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SourceMode {
+    /// Use the first source that knows the pinned version or has a matching
+    /// one, all lookups for a tag are answered by that one source.
+    #[default]
+    Fallback,
+    /// Only use versions every source knows, and require all sources to
+    /// agree on the hash of the picked version. Versions missing in any
+    /// source are never picked, even if they are the newest match of the
+    /// others.
+    Consensus,
+}
+
+impl SourceMode {
+    pub fn parse(unparsed: &str) -> Result<Self, String> {
+        match unparsed {
+            "fallback" => Ok(SourceMode::Fallback),
+            "consensus" => Ok(SourceMode::Consensus),
+            m => Err(format!(
+                "invalid source mode '{}', expected fallback or consensus",
+                m
+            )),
+        }
+    }
+}
+
+/// Combines the sources of a tag according to a `SourceMode`.
+pub struct MultiSource {
+    sources: Vec<Box<dyn VersionSource>>,
+    mode: SourceMode,
+}
+
+impl MultiSource {
+    pub fn new(sources: Vec<Box<dyn VersionSource>>, mode: SourceMode) -> Self {
+        Self { sources, mode }
+    }
+
+    /// Picks the source a fallback tag is resolved with: the first one that
+    /// knows the pinned version, or a version or channel the requirement asks
+    /// for. All lookups for the tag then use this one source.
+    pub fn into_fallback_source(
+        mut self,
+        requested_version: &scheme::VersionReq,
+        identifier: Option<&str>,
+        pinned: Option<&scheme::Version>,
+    ) -> Result<Box<dyn VersionSource>, String> {
+        let format = requested_version.get_format();
+        let mut err_msgs = vec![];
+        for (idx, source) in self.sources.iter().enumerate() {
+            let versions = match source.get_versions(identifier, format) {
+                Ok(versions) => versions,
+                Err(err_msg) => {
+                    err_msgs.push(format!("source {}: {}", idx + 1, err_msg));
+                    continue;
+                }
+            };
+            let is_pinned = versions.iter().any(|v| Some(v.get_version()) == pinned);
+            let is_usable = match requested_version.get_channel() {
+                Some(channel) => is_pinned || source.get_channel(identifier, channel).is_ok(),
+                None => {
+                    is_pinned
+                        || versions
+                            .iter()
+                            .any(|v| requested_version.matches(v.get_version()))
+                }
+            };
+            if is_usable {
+                return Ok(self.sources.remove(idx));
+            }
+            err_msgs.push(format!("source {}: no match found", idx + 1));
+        }
+        Err(format!("all sources failed ({})", err_msgs.join("; ")))
+    }
+
+    /// Tries every source in order and returns the first success, or all
+    /// errors if every source failed.
+    fn first_success<T>(
+        &self,
+        f: impl Fn(&dyn VersionSource) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut err_msgs = vec![];
+        for (idx, source) in self.sources.iter().enumerate() {
+            match f(source.as_ref()) {
+                Ok(result) => return Ok(result),
+                Err(err_msg) => err_msgs.push(format!("source {}: {}", idx + 1, err_msg)),
+            }
+        }
+        Err(format!("all sources failed ({})", err_msgs.join("; ")))
+    }
+
+    fn all_successful<T>(
+        &self,
+        f: impl Fn(&dyn VersionSource) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        self.sources
+            .iter()
+            .enumerate()
+            .map(|(idx, source)| {
+                f(source.as_ref()).map_err(|err_msg| format!("source {}: {}", idx + 1, err_msg))
+            })
+            .collect()
+    }
+}
+
+impl VersionSource for MultiSource {
+    fn get_versions(
+        &self,
         identifier: Option<&str>,
-    ) -> FixedVersionResult;
+        format: &scheme::VersionFormat,
+    ) -> Result<Vec<version::FixedVersion>, String> {
+        let get_versions = |source: &dyn VersionSource| source.get_versions(identifier, format);
+        match self.mode {
+            SourceMode::Fallback => self.first_success(get_versions),
+            SourceMode::Consensus => {
+                let mut all_versions = self.all_successful(get_versions)?.into_iter();
+                let first = all_versions.next().unwrap_or_default();
+                let others: Vec<_> = all_versions.collect();
+                Ok(first
+                    .into_iter()
+                    .filter(|v| {
+                        others.iter().all(|versions| {
+                            versions.iter().any(|o| o.get_version() == v.get_version())
+                        })
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    fn get_channel(&self, identifier: Option<&str>, channel: &str) -> Result<String, String> {
+        let get_channel = |source: &dyn VersionSource| source.get_channel(identifier, channel);
+        match self.mode {
+            SourceMode::Fallback => self.first_success(get_channel),
+            SourceMode::Consensus => {
+                let raw_versions = self.all_successful(get_channel)?;
+                match raw_versions.iter().find(|v| *v != &raw_versions[0]) {
+                    Some(other) => Err(format!(
+                        "sources disagree on channel @{}: {} vs {}",
+                        channel, raw_versions[0], other
+                    )),
+                    None => Ok(raw_versions[0].clone()),
+                }
+            }
+        }
+    }
+
+    fn get_identifiers(&self) -> Result<Vec<String>, String> {
+        let get_identifiers = |source: &dyn VersionSource| source.get_identifiers();
+        match self.mode {
+            SourceMode::Fallback => self.first_success(get_identifiers),
+            SourceMode::Consensus => {
+                let mut all_identifiers = self.all_successful(get_identifiers)?.into_iter();
+                let first = all_identifiers.next().unwrap_or_default();
+                let others: Vec<_> = all_identifiers.collect();
+                Ok(first
+                    .into_iter()
+                    .filter(|i| others.iter().all(|identifiers| identifiers.contains(i)))
+                    .collect())
+            }
+        }
+    }
+
+    fn get_fixed_version(
+        &self,
+        requested_version: &scheme::VersionReq,
+        identifier: Option<&str>,
+        policy: &ResolvePolicy,
+    ) -> FixedVersionResult {
+        let resolve_with = |source: &dyn VersionSource| {
+            source.get_fixed_version(requested_version, identifier, policy)
+        };
+        match self.mode {
+            SourceMode::Fallback => self.first_success(resolve_with),
+            SourceMode::Consensus => {
+                resolve_fixed_version(self, requested_version, identifier, policy).map_err(
+                    |err_msg| match self.first_success(resolve_with) {
+                        Ok(version) => format!(
+                            "{} ({} is not known to every source)",
+                            err_msg, version.raw_version
+                        ),
+                        Err(_) => err_msg,
+                    },
+                )
+            }
+        }
+    }
+
+    fn verify_version(
+        &self,
+        identifier: Option<&str>,
+        format: &scheme::VersionFormat,
+        version: &version::FixedVersion,
+    ) -> Result<(), String> {
+        if self.mode != SourceMode::Consensus {
+            return Ok(());
+        }
+
+        for (idx, source) in self.sources.iter().enumerate() {
+            let hash = source
+                .get_versions(identifier, format)?
+                .into_iter()
+                .find(|v| v.get_version() == version.get_version())
+                .map(|v| v.hash);
+            match hash {
+                Some(hash) if hash == version.hash => {}
+                Some(hash) => {
+                    return Err(format!(
+                        "sources disagree on the hash of {}: {} vs {} in source {}",
+                        version.raw_version,
+                        version.hash,
+                        hash,
+                        idx + 1
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "version {} is missing in source {}",
+                        version.raw_version,
+                        idx + 1
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ini_source::IniSource;
+
+    fn multi_source(filenames: &[&str], mode: SourceMode) -> MultiSource {
+        let sources = filenames
+            .iter()
+            .map(|f| Box::new(IniSource::new(f)) as Box<dyn VersionSource>)
+            .collect();
+        MultiSource::new(sources, mode)
+    }
+
+    fn resolve(sut: &MultiSource, identifier: &str) -> FixedVersionResult {
+        resolve_req(sut, "^1.0.0", identifier)
+    }
+
+    fn resolve_req(sut: &MultiSource, req: &str, identifier: &str) -> FixedVersionResult {
+        let req = scheme::VersionScheme::Semver.parse_req(req).unwrap();
+        let policy = ResolvePolicy::new(chrono::Utc::now());
+        sut.get_fixed_version(&req, Some(identifier), &policy)
+    }
+
+    #[test]
+    fn fallback_skips_failing_sources() {
+        let sut = multi_source(
+            &["./test/multi/missing.ini", "./test/multi/primary.ini"],
+            SourceMode::Fallback,
+        );
+
+        assert_eq!(resolve(&sut, "testapp").unwrap().get_hash(), "sha256:16");
+        assert!(resolve(&sut, "unknown").is_err());
+    }
+
+    #[test]
+    fn fallback_skips_sources_without_match() {
+        let sut = multi_source(
+            &["./test/multi/outdated.ini", "./test/multi/primary.ini"],
+            SourceMode::Fallback,
+        );
+
+        assert_eq!(resolve(&sut, "testapp").unwrap().get_hash(), "sha256:16");
+        assert_eq!(
+            resolve_req(&sut, "^0.9.0", "testapp").unwrap().get_hash(),
+            "sha256:09"
+        );
+    }
+
+    #[test]
+    fn fallback_source_knows_pinned_version() {
+        let req = scheme::VersionScheme::Semver.parse_req("^2.0.0").unwrap();
+        let pinned = scheme::VersionScheme::Semver
+            .parse_version("1.0.0")
+            .unwrap();
+        let get_versions = |pinned| {
+            multi_source(
+                &["./test/multi/outdated.ini", "./test/multi/primary.ini"],
+                SourceMode::Fallback,
+            )
+            .into_fallback_source(&req, Some("testapp"), pinned)?
+            .get_versions(Some("testapp"), req.get_format())
+        };
+
+        // only the second source knows 1.0.0, neither has a match for ^2.0.0
+        assert_eq!(get_versions(Some(&pinned)).unwrap().len(), 3);
+        assert!(get_versions(None).is_err());
+    }
+
+    #[test]
+    fn fallback_resolves_channel_with_one_source() {
+        let sut = multi_source(
+            &["./test/multi/outdated.ini", "./test/multi/primary.ini"],
+            SourceMode::Fallback,
+        );
+
+        // the first source has versions, but only the second one the channel
+        let version = resolve_req(&sut, "@stable", "testapp").unwrap();
+        assert_eq!(version.get_hash(), "sha256:15");
+    }
+
+    #[test]
+    fn consensus_uses_common_versions() {
+        let sut = multi_source(
+            &["./test/multi/primary.ini", "./test/multi/mirror.ini"],
+            SourceMode::Consensus,
+        );
+
+        // the mirror lacks 1.6.0, so the primary's newest is not picked
+        assert_eq!(resolve(&sut, "testapp").unwrap().raw_version, "1.5.0");
+    }
+
+    #[test]
+    fn consensus_reports_versions_missing_in_some_sources() {
+        let sut = multi_source(
+            &["./test/multi/primary.ini", "./test/multi/mirror.ini"],
+            SourceMode::Consensus,
+        );
+
+        let err_msg = resolve_req(&sut, "^1.6.0", "testapp").unwrap_err();
+        assert!(
+            err_msg.contains("1.6.0 is not known to every source"),
+            "{}",
+            err_msg
+        );
+        let err_msg = resolve_req(&sut, "^3.0.0", "testapp").unwrap_err();
+        assert_eq!(err_msg, "no match found");
+    }
+
+    #[test]
+    fn consensus_rejects_hash_mismatch() {
+        let sut = multi_source(
+            &["./test/multi/primary.ini", "./test/multi/mirror.ini"],
+            SourceMode::Consensus,
+        );
+
+        let err_msg = resolve(&sut, "tampered").unwrap_err();
+        assert!(err_msg.contains("disagree"), "{}", err_msg);
+    }
+
+    #[test]
+    fn source_mode_parse() {
+        assert_eq!(
+            SourceMode::parse("consensus").unwrap(),
+            SourceMode::Consensus
+        );
+        assert!(SourceMode::parse("majority").is_err());
+    }
 }
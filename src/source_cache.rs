@@ -0,0 +1,351 @@
+use crate::scheme;
+use crate::source::VersionSource;
+use crate::syntax;
+use crate::version;
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    /// use cached entries younger than the TTL, fetch and store the others
+    Normal,
+    /// only use cached entries, however old, and never fetch
+    Offline,
+    /// always fetch and store the result
+    Refresh,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    pub mode: CacheMode,
+    pub ttl: Duration,
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            mode: CacheMode::Normal,
+            ttl: Duration::hours(1),
+            dir: get_default_dir(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref OPTIONS: RwLock<CacheOptions> = RwLock::new(CacheOptions::default());
+}
+
+/// Sets the options for all sources created afterwards.
+pub fn configure(options: CacheOptions) {
+    *OPTIONS.write().unwrap() = options;
+}
+
+fn get_options() -> CacheOptions {
+    OPTIONS.read().unwrap().clone()
+}
+
+/// `$DEMVER_CACHE_DIR`, `$XDG_CACHE_HOME/demver` or `~/.cache/demver`.
+pub fn get_default_dir() -> Option<PathBuf> {
+    let env_dir = |name| std::env::var_os(name).filter(|dir| !dir.is_empty());
+    if let Some(dir) = env_dir("DEMVER_CACHE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env_dir("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(dir).join("demver"));
+    }
+    env_dir("HOME").map(|dir| PathBuf::from(dir).join(".cache").join("demver"))
+}
+
+/// FNV-1a, which unlike the std hasher is stable across builds.
+fn get_stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Serves the versions and channels of a remote source from the on-disk
+/// cache. Each lookup is an entry of its own, keyed by the source and the
+/// identifier.
+pub struct CachedSource {
+    source: Box<dyn VersionSource>,
+    key: String,
+    options: CacheOptions,
+}
+
+impl CachedSource {
+    /// Wraps sources that can be cached, others are returned as they are.
+    pub fn wrap(source: Box<dyn VersionSource>) -> Box<dyn VersionSource> {
+        Self::wrap_with(source, get_options())
+    }
+
+    fn wrap_with(source: Box<dyn VersionSource>, options: CacheOptions) -> Box<dyn VersionSource> {
+        match source.get_cache_key() {
+            Some(key) => Box::new(CachedSource {
+                source,
+                key,
+                options,
+            }),
+            None => source,
+        }
+    }
+
+    fn get_entry_path(&self, entry: &str) -> Option<PathBuf> {
+        let hash = get_stable_hash(&format!("{}\n{}", self.key, entry));
+        let dir = self.options.dir.as_ref()?;
+        Some(dir.join(format!("{:016x}.json", hash)))
+    }
+
+    fn read_entry(&self, path: &Path, entry: &str) -> Option<Value> {
+        let content = std::fs::read_to_string(path).ok()?;
+        let cached: Value = serde_json::from_str(&content).ok()?;
+        if cached["source"] != self.key.as_str() || cached["entry"] != entry {
+            return None;
+        }
+        let fetched = syntax::parse_timestamp(cached["fetched"].as_str()?).ok()?;
+        let is_fresh = Utc::now() - fetched < self.options.ttl;
+        match self.options.mode {
+            CacheMode::Offline => Some(cached["value"].clone()),
+            CacheMode::Normal if is_fresh => Some(cached["value"].clone()),
+            _ => None,
+        }
+    }
+
+    /// A cache that can't be written only makes the next run slower. Entries
+    /// are replaced atomically, as concurrent lookups may write the same one.
+    fn write_entry(&self, path: &Path, entry: &str, value: Value) {
+        let cached = json!({
+            "source": self.key,
+            "entry": entry,
+            "fetched": syntax::format_timestamp(&Utc::now()),
+            "value": value,
+        });
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => return,
+        };
+        let _ = std::fs::create_dir_all(dir);
+        if let Ok(mut tmp_file) = tempfile::NamedTempFile::new_in(dir) {
+            if tmp_file.write_all(cached.to_string().as_bytes()).is_ok() {
+                let _ = tmp_file.persist(path);
+            }
+        }
+    }
+
+    fn lookup<T>(
+        &self,
+        entry: &str,
+        fetch: impl Fn() -> Result<T, String>,
+        to_json: impl Fn(&T) -> Value,
+        from_json: impl Fn(&Value) -> Option<T>,
+    ) -> Result<T, String> {
+        let path = self.get_entry_path(entry);
+        let cached = path
+            .as_ref()
+            .and_then(|path| self.read_entry(path, entry))
+            .and_then(|value| from_json(&value));
+        if let Some(value) = cached {
+            return Ok(value);
+        }
+        if self.options.mode == CacheMode::Offline {
+            return Err(format!(
+                "{} of {} is not cached, which is required in offline mode",
+                entry, self.key
+            ));
+        }
+
+        let value = fetch()?;
+        if let Some(path) = path {
+            self.write_entry(&path, entry, to_json(&value));
+        }
+        Ok(value)
+    }
+}
+
+impl VersionSource for CachedSource {
+    fn get_versions(
+        &self,
+        identifier: Option<&str>,
+        format: &scheme::VersionFormat,
+    ) -> Result<Vec<version::FixedVersion>, String> {
+        let entry = format!(
+            "versions of {} ({:?}, {:?})",
+            identifier.unwrap_or_default(),
+            format.scheme,
+            format.variant
+        );
+        self.lookup(
+            &entry,
+            || self.source.get_versions(identifier, format),
+            |versions| {
+                versions
+                    .iter()
+                    .map(|v| {
+                        json!({
+                            "version": v.raw_version,
+                            "hash": v.hash,
+                            "released": v.released.as_ref().map(syntax::format_timestamp),
+                        })
+                    })
+                    .collect()
+            },
+            |value| {
+                value
+                    .as_array()?
+                    .iter()
+                    .map(|v| {
+                        let raw_version = v["version"].as_str()?;
+                        let released = match v["released"].as_str() {
+                            Some(released) => Some(syntax::parse_timestamp(released).ok()?),
+                            None => None,
+                        };
+                        Some(version::FixedVersion {
+                            raw_version: raw_version.to_owned(),
+                            version: format.parse_version(raw_version).ok()?,
+                            hash: v["hash"].as_str()?.to_owned(),
+                            released,
+                        })
+                    })
+                    .collect()
+            },
+        )
+    }
+
+    fn get_channel(&self, identifier: Option<&str>, channel: &str) -> Result<String, String> {
+        let entry = format!("channel @{} of {}", channel, identifier.unwrap_or_default());
+        self.lookup(
+            &entry,
+            || self.source.get_channel(identifier, channel),
+            |raw_version| json!(raw_version),
+            |value| value.as_str().map(String::from),
+        )
+    }
+
+    fn get_identifiers(&self) -> Result<Vec<String>, String> {
+        self.lookup(
+            "identifiers",
+            || self.source.get_identifiers(),
+            |identifiers| json!(identifiers),
+            |value| serde_json::from_value(value.clone()).ok(),
+        )
+    }
+
+    fn verify_version(
+        &self,
+        identifier: Option<&str>,
+        format: &scheme::VersionFormat,
+        version: &version::FixedVersion,
+    ) -> Result<(), String> {
+        self.source.verify_version(identifier, format, version)
+    }
+
+    fn get_cache_key(&self) -> Option<String> {
+        Some(self.key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ini_source::IniSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Pretends the versions of an ini file are fetched from a server.
+    struct RemoteSource {
+        ini: IniSource,
+        nr_fetches: Arc<AtomicUsize>,
+    }
+
+    impl VersionSource for RemoteSource {
+        fn get_versions(
+            &self,
+            identifier: Option<&str>,
+            format: &scheme::VersionFormat,
+        ) -> Result<Vec<version::FixedVersion>, String> {
+            self.nr_fetches.fetch_add(1, Ordering::SeqCst);
+            self.ini.get_versions(identifier, format)
+        }
+
+        fn get_cache_key(&self) -> Option<String> {
+            Some("remote(example.com)".to_owned())
+        }
+    }
+
+    fn get_remote_source(options: &CacheOptions) -> (Box<dyn VersionSource>, Arc<AtomicUsize>) {
+        let nr_fetches = Arc::new(AtomicUsize::new(0));
+        let source = RemoteSource {
+            ini: IniSource::new("./test/cooldown/versions.ini"),
+            nr_fetches: nr_fetches.clone(),
+        };
+        (
+            CachedSource::wrap_with(Box::new(source), options.clone()),
+            nr_fetches,
+        )
+    }
+
+    fn get_versions(source: &dyn VersionSource) -> Result<Vec<String>, String> {
+        let mut versions: Vec<String> = source
+            .get_versions(Some("testapp"), &scheme::VersionFormat::default())?
+            .into_iter()
+            .map(|v| format!("{}={}", v.raw_version, v.hash))
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    #[test]
+    fn cache_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = CacheOptions {
+            mode: CacheMode::Offline,
+            ttl: Duration::hours(1),
+            dir: Some(dir.path().to_path_buf()),
+        };
+
+        let (source, _) = get_remote_source(&options);
+        let err_msg = get_versions(source.as_ref()).unwrap_err();
+        assert!(err_msg.contains("offline mode"), "{}", err_msg);
+
+        options.mode = CacheMode::Normal;
+        let (source, nr_fetches) = get_remote_source(&options);
+        let fetched = get_versions(source.as_ref()).unwrap();
+        assert_eq!(get_versions(source.as_ref()).unwrap(), fetched);
+        assert_eq!(nr_fetches.load(Ordering::SeqCst), 1);
+
+        options.mode = CacheMode::Offline;
+        let (source, nr_fetches) = get_remote_source(&options);
+        assert_eq!(get_versions(source.as_ref()).unwrap(), fetched);
+        assert_eq!(nr_fetches.load(Ordering::SeqCst), 0);
+
+        options.mode = CacheMode::Refresh;
+        let (source, nr_fetches) = get_remote_source(&options);
+        get_versions(source.as_ref()).unwrap();
+        assert_eq!(nr_fetches.load(Ordering::SeqCst), 1);
+
+        options.mode = CacheMode::Normal;
+        options.ttl = Duration::zero();
+        let (source, nr_fetches) = get_remote_source(&options);
+        get_versions(source.as_ref()).unwrap();
+        assert_eq!(nr_fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn local_sources_are_not_cached() {
+        let options = CacheOptions {
+            mode: CacheMode::Offline,
+            ttl: Duration::hours(1),
+            dir: None,
+        };
+        let source = CachedSource::wrap_with(
+            Box::new(IniSource::new("./test/cooldown/versions.ini")),
+            options,
+        );
+
+        assert!(get_versions(source.as_ref()).is_ok());
+        assert_eq!(source.get_cache_key(), None);
+    }
+}
@@ -0,0 +1,51 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type Shared = Arc<dyn Any + Send + Sync>;
+
+lazy_static! {
+    /// The source data used during this run, e.g. parsed files, by a key
+    /// identifying it.
+    static ref LOADED: Mutex<HashMap<String, Shared>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the data for the key, creating it on first use. Data is read only
+/// once, so all tags referring to the same source are served from memory.
+pub fn get_or_create<T: Any + Send + Sync>(key: &str, create: impl FnOnce() -> T) -> Arc<T> {
+    // the type is part of the key, so the downcast can't fail
+    let key = format!("{}|{}", std::any::type_name::<T>(), key);
+    let shared = LOADED
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(create()))
+        .clone();
+    shared.downcast().unwrap()
+}
+
+/// Forgets all loaded data, so that it is read again after files changed.
+pub fn clear() {
+    LOADED.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ini_source::IniFile;
+
+    #[test]
+    fn data_is_shared() {
+        let mut nr_created = 0;
+        let mut create = || {
+            nr_created += 1;
+            IniFile::new("./test/simple/versions.ini")
+        };
+
+        let first = get_or_create("test(data_is_shared)", &mut create);
+        let second = get_or_create("test(data_is_shared)", &mut create);
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(nr_created, 1);
+    }
+}
@@ -1,22 +1,58 @@
-use regex::Regex;
+use crate::comment::CommentSyntax;
+use crate::policy;
+use crate::scheme;
+use crate::source;
+use crate::tag_parser::{self, NamedField, TagError, TagSyntax};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// Parses a timestamp as written in tags, e.g. `2020-12-05T18-18-09` (UTC).
+pub fn parse_timestamp(unparsed: &str) -> Result<DateTime<Utc>, String> {
+    match NaiveDateTime::parse_from_str(unparsed, TIMESTAMP_FORMAT) {
+        Ok(t) => Ok(Utc.from_utc_datetime(&t)),
+        Err(e) => Err(format!("Failed to parse timestamp '{}': {}", unparsed, e)),
+    }
+}
+
+pub fn format_timestamp(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Where the fields of a tag start in the file, to point errors at them.
+#[derive(Debug, Clone, Default)]
+struct FieldOffsets {
+    version_req: usize,
+    version_source: usize,
+    attributes: usize,
+    current_version: usize,
+    timestamp: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct TokenizedTag {
     version_req: String,
     version_source: String,
     identifier: String,
+    attributes: String,
     current_version: String,
     timestamp: String,
     idx_start: usize,
     idx_end: usize,
     origin_filename: String,
+    offsets: FieldOffsets,
+    syntax: TagSyntax,
+    fields: Vec<NamedField>,
 }
 
 impl TokenizedTag {
+    #[cfg(test)]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         version_req: &str,
         version_source: &str,
         identifier: &str,
+        attributes: &str,
         current_version: &str,
         timestamp: &str,
         idx_start: usize,
@@ -27,97 +63,367 @@ impl TokenizedTag {
             version_req: version_req.to_owned(),
             version_source: version_source.to_owned(),
             identifier: identifier.to_owned(),
+            attributes: attributes.to_owned(),
             current_version: current_version.to_owned(),
             timestamp: timestamp.to_owned(),
             idx_start,
             idx_end,
             origin_filename: origin_filename.to_owned(),
+            offsets: FieldOffsets::default(),
+            syntax: TagSyntax::V1,
+            fields: vec![],
         }
     }
 
-    fn handle_captures(filename: &str, captures: &regex::Captures) -> Result<Self, String> {
-        let entire_match = captures.get(0).unwrap();
-        let start = entire_match.start();
-        let end = entire_match.end();
-        let semver = match captures.get(1) {
-            Some(s) => s,
-            None => return Err("Unable to extract the semver from demver tag".to_string()),
-        };
-        let version_source = match captures.get(2) {
-            Some(s) => s,
-            None => return Err("Unable to extract the version_source from demver tag".to_string()),
-        };
-        let identifier = match captures.get(3) {
-            Some(s) => s,
-            None => return Err("Unable to extract the identifier from demver tag".to_string()),
-        };
-        let current_version = match captures.get(4) {
-            Some(s) => s,
-            None => return Err("Unable to extract the current version from demver tag".to_string()),
-        };
-        let timestamp = match captures.get(5) {
-            Some(s) => s,
-            None => return Err("Unable to extract the timestamp from demver tag".to_string()),
-        };
+    fn from_raw(filename: &str, mut raw: tag_parser::RawTag) -> Self {
+        // version 2 tags write attributes as fields of their own
+        let attribute_fields: Vec<&NamedField> = raw
+            .fields
+            .iter()
+            .filter(|f| TagAttributes::KEYS.contains(&f.key.as_str()))
+            .collect();
+        if let Some(first) = attribute_fields.first() {
+            raw.attributes.offset = first.value.offset;
+            raw.attributes.value = attribute_fields
+                .iter()
+                .map(|f| format!("{}={}", f.key, f.value.value))
+                .collect::<Vec<_>>()
+                .join(",");
+        }
 
-        Ok(Self::new(
-            semver.as_str(),
-            version_source.as_str(),
-            identifier.as_str(),
-            current_version.as_str(),
-            timestamp.as_str(),
-            start,
-            end,
-            filename,
-        ))
+        Self {
+            offsets: FieldOffsets {
+                version_req: raw.version_req.offset,
+                version_source: raw.version_source.offset,
+                attributes: raw.attributes.offset,
+                current_version: raw.current_version.offset,
+                timestamp: raw.timestamp.offset,
+            },
+            version_req: raw.version_req.value,
+            version_source: raw.version_source.value,
+            identifier: raw.identifier.value,
+            attributes: raw.attributes.value,
+            current_version: raw.current_version.value,
+            timestamp: raw.timestamp.value,
+            idx_start: raw.idx_start,
+            idx_end: raw.idx_end,
+            origin_filename: filename.to_owned(),
+            syntax: raw.syntax,
+            fields: raw.fields,
+        }
     }
 
+    /// Tokenizes all tags within comments. Malformed tags are returned as
+    /// errors pointing at the problem instead of being skipped.
     pub fn tokenize_all(
         filename: &str,
         unparsed: &str,
         max_nr_tags: usize,
-    ) -> Vec<Result<Self, String>> {
-        lazy_static! {
-            static ref RE: Regex =
-                Regex::new(r"\[demver\((.+?)\)\|(.+?)\|(.+?)\]\s([^\s]+)\s@\s([^\s]+)").unwrap();
+    ) -> Vec<Result<Self, TagError>> {
+        let comment_syntax = CommentSyntax::for_filename(filename);
+        let max_nr_tags = match max_nr_tags {
+            0 => usize::MAX,
+            n => n,
+        };
+
+        tag_parser::parse_all(unparsed, &comment_syntax)
+            .into_iter()
+            .take(max_nr_tags)
+            .map(|result| result.map(|raw| Self::from_raw(filename, raw)))
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn tokenize_one(filename: &str, unparsed: &str) -> Result<Self, String> {
+        let mut vec = Self::tokenize_all(filename, unparsed, 1);
+
+        if vec.is_empty() {
+            return Err("no match was found".to_owned());
         }
 
-        let mut ret = Vec::new();
+        vec.remove(0).map_err(|e| e.message)
+    }
 
-        for cap in RE.captures_iter(unparsed) {
-            ret.push(Self::handle_captures(filename, &cap));
-            if max_nr_tags != 0 && ret.len() >= max_nr_tags {
-                break;
+    pub fn format(
+        version_req: &str,
+        version_source: &str,
+        identifier: &str,
+        attributes: &str,
+        current_version: &str,
+        timestamp: &str,
+    ) -> String {
+        let attributes = match attributes {
+            "" => "".to_owned(),
+            a => format!("|{}", a),
+        };
+        format!(
+            "[demver({})|{}|{}{}] {} @ {}",
+            version_req, version_source, identifier, attributes, current_version, timestamp
+        )
+    }
+
+    /// Renders `[demver:2 key=value ...] version @ timestamp`.
+    pub fn format_v2(fields: &[(&str, &str)], current_version: &str, timestamp: &str) -> String {
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(key, raw_value)| format!("{}={}", key, raw_value))
+            .collect();
+        format!(
+            "[demver:2 {}] {} @ {}",
+            fields.join(" "),
+            current_version,
+            timestamp
+        )
+    }
+
+    /// Renders this tag in the syntax it was written in. Fields of a version
+    /// 2 tag are kept as they were, including unknown ones.
+    fn format_as_written(&self, current_version: &str, timestamp: &str) -> String {
+        match self.syntax {
+            TagSyntax::V1 => Self::format(
+                &self.version_req,
+                &self.version_source,
+                &self.identifier,
+                &self.attributes,
+                current_version,
+                timestamp,
+            ),
+            TagSyntax::V2 => {
+                let fields: Vec<(&str, &str)> = self
+                    .fields
+                    .iter()
+                    .map(|f| (f.key.as_str(), f.raw_value.as_str()))
+                    .collect();
+                Self::format_v2(&fields, current_version, timestamp)
             }
         }
+    }
+
+    /// Renders this tag in version 2 syntax, keeping everything else.
+    pub fn to_v2(&self) -> String {
+        if self.syntax == TagSyntax::V2 {
+            return self.format_as_written(&self.current_version, &self.timestamp);
+        }
 
-        ret
+        let mut values = vec![
+            ("req", tag_parser::quote_field(&self.version_req)),
+            ("src", tag_parser::quote_field(&self.version_source)),
+            ("id", tag_parser::quote_field(&self.identifier)),
+        ];
+        for attribute in self.attributes.split(',').filter(|a| !a.is_empty()) {
+            if let Some((key, value)) = attribute.split_once('=') {
+                values.push((key.trim(), tag_parser::quote_field(value.trim())));
+            }
+        }
+        let fields: Vec<(&str, &str)> = values.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        Self::format_v2(&fields, &self.current_version, &self.timestamp)
     }
 
-    fn tokenize_one(filename: &str, unparsed: &str) -> Result<Self, String> {
-        let mut vec = Self::tokenize_all(filename, unparsed, 1);
+    pub fn get_syntax(&self) -> TagSyntax {
+        self.syntax
+    }
 
-        if vec.len() < 1 {
-            return Err("no match was found".to_owned());
+    /// Changes the requirement, also in the fields of a version 2 tag.
+    fn set_version_req(&mut self, version_req: &str) {
+        self.version_req = version_req.to_owned();
+        if let Some(field) = self.fields.iter_mut().find(|f| f.key == "req") {
+            field.raw_value = tag_parser::quote_field(version_req);
+            field.value.value = version_req.to_owned();
+        }
+    }
+
+    pub fn get_idx_start(&self) -> usize {
+        self.idx_start
+    }
+
+    pub fn get_idx_end(&self) -> usize {
+        self.idx_end
+    }
+}
+
+/// Splits at `separator` outside of parentheses and quotes. Within quotes,
+/// `\` escapes the next character.
+fn split_top_level(unparsed: &str, separator: char) -> Result<Vec<&str>, String> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (idx, c) in unparsed.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return Err(format!("unbalanced ')' in '{}'", unparsed)),
+            (None, ')') => depth -= 1,
+            (None, c) if c == separator && depth == 0 => {
+                parts.push(&unparsed[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in '{}'", unparsed));
+    }
+    if depth > 0 {
+        return Err(format!("unclosed '(' in '{}'", unparsed));
+    }
+    parts.push(&unparsed[start..]);
+    Ok(parts)
+}
+
+/// Quotes a source argument if it would not be read back as it is.
+pub fn format_argument(value: &str) -> String {
+    let is_plain = |c: char| !c.is_whitespace() && !"\"'\\,()=|[]".contains(c);
+    match value {
+        v if !v.is_empty() && v.chars().all(is_plain) => v.to_owned(),
+        v => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+/// The arguments within the parentheses of a source, e.g.
+/// `file(path=versions.ini, section=apps)`. Arguments without a key are
+/// positional. Values may be quoted with `"` or `'`.
+#[derive(Debug, Default, PartialEq)]
+pub struct SourceArguments {
+    positional: Vec<String>,
+    named: Vec<(String, String)>,
+}
+
+impl SourceArguments {
+    pub fn parse(unparsed: &str) -> Result<Self, String> {
+        let mut arguments = Self::default();
+        if unparsed.trim().is_empty() {
+            return Ok(arguments);
+        }
+
+        for argument in split_top_level(unparsed, ',')? {
+            let argument = argument.trim();
+            // a `=` within a quoted value doesn't start a value
+            let key_end = argument
+                .find(['=', '"', '\''])
+                .filter(|idx| argument[*idx..].starts_with('='));
+            let (key, value) = match key_end {
+                Some(idx) => (Some(argument[..idx].trim()), argument[idx + 1..].trim()),
+                None => (None, argument),
+            };
+            if value.is_empty() {
+                return Err(format!("empty source argument '{}'", argument));
+            }
+            let value = tag_parser::unquote(value)?;
+
+            match key {
+                Some(key)
+                    if key.is_empty()
+                        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    return Err(format!("invalid source argument name '{}'", key));
+                }
+                Some(key) if arguments.named.iter().any(|(k, _)| k == key) => {
+                    return Err(format!("source argument '{}' is given twice", key));
+                }
+                Some(key) => arguments.named.push((key.to_owned(), value)),
+                None if !arguments.named.is_empty() => {
+                    return Err(format!(
+                        "positional source argument '{}' follows a named one",
+                        value
+                    ));
+                }
+                None => arguments.positional.push(value),
+            }
+        }
+
+        Ok(arguments)
+    }
+
+    /// Takes the argument with the given name, or the next positional one.
+    pub fn take(&mut self, key: &str, positional: bool) -> Option<String> {
+        match self.named.iter().position(|(k, _)| k == key) {
+            Some(idx) => Some(self.named.remove(idx).1),
+            None if positional && !self.positional.is_empty() => Some(self.positional.remove(0)),
+            None => None,
         }
+    }
 
-        vec.remove(0)
+    /// Fails if there are arguments the source doesn't know about.
+    pub fn finish(self, source_type: &str) -> Result<(), String> {
+        if let Some(value) = self.positional.first() {
+            return Err(format!(
+                "unexpected argument '{}' for {} source",
+                value, source_type
+            ));
+        }
+        match self.named.first() {
+            Some((key, _)) => Err(format!(
+                "unknown argument '{}' for {} source",
+                key, source_type
+            )),
+            None => Ok(()),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    Ini,
+}
+
+impl FileFormat {
+    fn parse(unparsed: &str) -> Result<Self, String> {
+        match unparsed {
+            "ini" => Ok(FileFormat::Ini),
+            f => Err(format!(
+                "unsupported file format '{}', only 'ini' is supported",
+                f
+            )),
+        }
+    }
+
+    fn for_filename(filename: &str) -> Result<Self, String> {
+        match std::path::Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+        {
+            Some("ini") => Ok(FileFormat::Ini),
+            _ => Err(format!(
+                "can't tell the format of '{}' from its extension, add format=ini",
+                filename
+            )),
+        }
+    }
+}
+
+/// `file(versions.ini)` or `file(path=versions.ini, section=apps, format=ini)`.
+/// Without a `section`, the identifier of the tag is looked up.
 #[derive(Debug, PartialEq)]
 pub struct FileSourceTag {
     pub filename: String,
+    pub section: Option<String>,
+    pub format: FileFormat,
 }
 
 impl FileSourceTag {
-    fn parse(unparsed_arguments: &str) -> Result<Self, String> {
-        match unparsed_arguments.len() {
-            0 => Err("no filename was given".to_owned()),
-            _ => Ok(FileSourceTag {
-                filename: unparsed_arguments.to_owned(),
-            }),
-        }
+    fn parse(mut arguments: SourceArguments) -> Result<Self, String> {
+        let filename = match arguments.take("path", true) {
+            Some(filename) => filename,
+            None => return Err("no filename was given".to_owned()),
+        };
+        let section = arguments.take("section", false);
+        let format = match arguments.take("format", false) {
+            Some(format) => FileFormat::parse(&format)?,
+            None => FileFormat::for_filename(&filename)?,
+        };
+        arguments.finish("file")?;
+
+        Ok(FileSourceTag {
+            filename,
+            section,
+            format,
+        })
     }
 }
 
@@ -128,65 +434,141 @@ pub enum SourceTag {
 
 impl SourceTag {
     fn parse(unparsed: &str) -> Result<Self, String> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"^([^\(]+)(?:\((.+)\))?").unwrap();
+        let (source_tag_type, arguments) = match unparsed.find('(') {
+            Some(idx) if unparsed.ends_with(')') => {
+                (&unparsed[..idx], &unparsed[idx + 1..unparsed.len() - 1])
+            }
+            Some(_) => return Err(format!("expected ')' at the end of source '{}'", unparsed)),
+            None => (unparsed, ""),
+        };
+        let arguments = SourceArguments::parse(arguments)?;
+
+        match source_tag_type.trim() {
+            "file" => Ok(SourceTag::File(FileSourceTag::parse(arguments)?)),
+            t => Err(format!("unknown version_source tag type '{}'", t)),
         }
+    }
 
-        let captures = match RE.captures(unparsed) {
-            Some(c) => c,
-            None => return Err("Source Tag could not be parsed".to_owned()),
-        };
+    /// Parses a comma separated list of sources like
+    /// `file(a.ini),file(b.ini)`. Commas within parentheses are kept.
+    pub fn parse_list(unparsed: &str) -> Result<Vec<Self>, String> {
+        split_top_level(unparsed, ',')?
+            .into_iter()
+            .map(|source| SourceTag::parse(source.trim()))
+            .collect()
+    }
+}
 
-        let source_tag_type = captures.get(1).unwrap().as_str();
-        let source_arguments = match captures.get(2) {
-            Some(m) => m.as_str(),
-            None => "",
-        };
+/// Optional `key=value` settings following the identifier of a tag, e.g.
+/// `[demver(^1.0.0)|file(versions.ini)|testapp|prerelease=rc]`.
+#[derive(Debug, Default, PartialEq)]
+pub struct TagAttributes {
+    pub prerelease: Option<policy::PrereleasePolicy>,
+    pub scheme: scheme::VersionScheme,
+    pub variant: Option<String>,
+    pub sources: source::SourceMode,
+    pub group: Option<String>,
+}
 
-        match source_tag_type {
-            "file" => {
-                let file_source_tag = FileSourceTag::parse(source_arguments)?;
-                Ok(SourceTag::File(file_source_tag))
+impl TagAttributes {
+    pub const KEYS: &'static [&'static str] =
+        &["prerelease", "scheme", "variant", "sources", "group"];
+
+    pub fn parse(unparsed: &str) -> Result<Self, String> {
+        let mut attributes = TagAttributes::default();
+        if unparsed.is_empty() {
+            return Ok(attributes);
+        }
+
+        for attribute in unparsed.split(',') {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(format!("tag attribute '{}' is missing a value", attribute)),
+            };
+            match key {
+                "prerelease" => {
+                    attributes.prerelease = Some(policy::PrereleasePolicy::parse(value)?)
+                }
+                "scheme" => attributes.scheme = scheme::VersionScheme::parse(value)?,
+                "variant" => attributes.variant = Some(parse_name("variant", value)?),
+                "group" => attributes.group = Some(parse_name("group", value)?),
+                "sources" => attributes.sources = source::SourceMode::parse(value)?,
+                k => return Err(format!("unknown tag attribute '{}'", k)),
             }
-            t => Err(format!("unknown version_source tag type '{}'", t)),
         }
+
+        Ok(attributes)
+    }
+
+    pub fn get_format(&self) -> scheme::VersionFormat {
+        scheme::VersionFormat {
+            scheme: self.scheme,
+            variant: self.variant.clone(),
+        }
+    }
+}
+
+fn parse_name(kind: &str, unparsed: &str) -> Result<String, String> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_';
+    match unparsed {
+        v if !v.is_empty() && v.chars().all(is_valid) => Ok(v.to_owned()),
+        v => Err(format!("invalid {} '{}'", kind, v)),
     }
 }
 
 #[derive(Debug)]
 pub struct DemverTag {
     tokenized_tag: TokenizedTag,
-    pub semver: semver::VersionReq,
-    pub version_source: SourceTag,
+    pub version_req: scheme::VersionReq,
+    pub version_sources: Vec<SourceTag>,
     pub identifier: String,
-    current_version: semver::Version,
-    timestamp: String,
+    pub attributes: TagAttributes,
+    current_version: scheme::Version,
+    timestamp: DateTime<Utc>,
 }
 
 impl DemverTag {
-    pub fn parse(unparsed: &TokenizedTag) -> Result<Self, String> {
-        let semver = match semver::VersionReq::parse(&unparsed.version_req) {
-            Ok(v) => v,
-            Err(e) => return Err(format!("Failed to parse semver: {}", e)),
+    /// Interprets the fields of a tag. Errors point at the offending field.
+    pub fn parse(unparsed: &TokenizedTag) -> Result<Self, TagError> {
+        let offsets = &unparsed.offsets;
+        let at = |offset: usize, field: &'static str| {
+            move |e: String| TagError::new(offset, &format!("bad {}: {}", field, e))
         };
-        let version_source = SourceTag::parse(&unparsed.version_source)?;
+
+        let attributes = TagAttributes::parse(&unparsed.attributes)
+            .map_err(at(offsets.attributes, "attributes"))?;
+        let format = attributes.get_format();
+        let version_req = format
+            .parse_req(&unparsed.version_req)
+            .map_err(at(offsets.version_req, "requirement"))?;
+        let version_sources = SourceTag::parse_list(&unparsed.version_source)
+            .map_err(at(offsets.version_source, "source"))?;
         let identifier = unparsed.identifier.clone();
-        let current_version = match semver::Version::parse(&unparsed.current_version) {
-            Ok(v) => v,
-            Err(e) => return Err(format!("Failed to parse semver: {}", e)),
-        };
-        let timestamp = unparsed.timestamp.clone();
+        let current_version = format
+            .parse_version(&unparsed.current_version)
+            .map_err(at(offsets.current_version, "pinned version"))?;
+        let timestamp =
+            parse_timestamp(&unparsed.timestamp).map_err(at(offsets.timestamp, "timestamp"))?;
 
         Ok(DemverTag {
-            tokenized_tag: (*unparsed).clone(),
-            semver,
-            version_source,
+            tokenized_tag: unparsed.clone(),
+            version_req,
+            version_sources,
             identifier,
+            attributes,
             current_version,
             timestamp,
         })
     }
 
+    /// Tokenizes and parses all tags of a file.
+    pub fn parse_all(filename: &str, content: &str) -> Vec<Result<Self, TagError>> {
+        TokenizedTag::tokenize_all(filename, content, 0)
+            .into_iter()
+            .map(|result| result.and_then(|tag| Self::parse(&tag)))
+            .collect()
+    }
+
     pub fn get_raw_version_req(&self) -> &String {
         &self.tokenized_tag.version_req
     }
@@ -198,6 +580,37 @@ impl DemverTag {
     pub fn get_origin_filename(&self) -> &String {
         &self.tokenized_tag.origin_filename
     }
+
+    pub fn get_current_version(&self) -> &scheme::Version {
+        &self.current_version
+    }
+
+    pub fn get_raw_timestamp(&self) -> &String {
+        &self.tokenized_tag.timestamp
+    }
+
+    /// Time since the tag was last updated.
+    pub fn get_age(&self, now: &DateTime<Utc>) -> chrono::Duration {
+        *now - self.timestamp
+    }
+
+    pub fn get_tokenized_tag(&self) -> &TokenizedTag {
+        &self.tokenized_tag
+    }
+
+    /// Returns a copy of this tag with a different requirement.
+    pub fn with_version_req(&self, version_req: &str) -> Result<Self, String> {
+        let mut tokenized_tag = self.tokenized_tag.clone();
+        tokenized_tag.set_version_req(version_req);
+        Self::parse(&tokenized_tag).map_err(|e| e.message)
+    }
+
+    /// Renders this tag with a new pinned version and timestamp, keeping
+    /// the requirement, source and identifier as they were written.
+    pub fn format_with_version(&self, current_version: &str, timestamp: &str) -> String {
+        self.tokenized_tag
+            .format_as_written(current_version, timestamp)
+    }
 }
 
 #[cfg(test)]
@@ -298,6 +711,7 @@ mod tests {
             version_req,
             version_source,
             identifier,
+            "",
             current_version,
             timestamp,
             0,
@@ -306,7 +720,181 @@ mod tests {
         ))
         .unwrap();
 
-        // println!("{:?}", sut);
+        assert_eq!(sut.identifier, identifier);
+        assert_eq!(sut.get_current_version().to_string(), current_version);
+        assert_eq!(sut.get_raw_timestamp(), timestamp);
+        assert_eq!(format_timestamp(&sut.timestamp), timestamp);
+    }
+
+    #[test]
+    fn with_version_req() {
+        let tokenized_tag = TokenizedTag::tokenize_one(TEST_FILENAME, TEST_STRING).unwrap();
+        let sut = DemverTag::parse(&tokenized_tag).unwrap();
+
+        let bumped = sut.with_version_req("^2.0.0").unwrap();
+        assert_eq!(bumped.get_raw_version_req(), "^2.0.0");
+        assert_eq!(
+            bumped.format_with_version("2.0.0", "2021-01-02T03-04-05"),
+            "[demver(^2.0.0)|file(versions.ini)|testapp] 2.0.0 @ 2021-01-02T03-04-05"
+        );
+        assert!(sut.with_version_req("^two").is_err());
+    }
+
+    #[test]
+    fn parse_demver_tag_invalid_timestamp() {
+        let sut = DemverTag::parse(&TokenizedTag::new(
+            "^1.0.0",
+            "file(versions.ini)",
+            "testapp",
+            "",
+            "1.0.0",
+            "2020-12-05 18:18:09",
+            0,
+            0,
+            TEST_FILENAME,
+        ));
+
+        assert!(sut.is_err());
+    }
+
+    #[test]
+    fn format_roundtrip() {
+        let formatted = TokenizedTag::format(
+            "^1.0.0",
+            "file(versions.ini)",
+            "testapp",
+            "",
+            "1.0.0",
+            "2020-12-05T18-18-09",
+        );
+
+        assert_eq!(formatted, &TEST_STRING[2..]);
+    }
+
+    #[test]
+    fn tokenize_one_attributes() {
+        let sut = TokenizedTag::tokenize_one(
+            TEST_FILENAME,
+            "# [demver(^1.0.0)|file(versions.ini)|testapp|prerelease=rc] 1.0.0 @ 2020-12-05T18-18-09",
+        )
+        .unwrap();
+
+        assert_eq!(sut.identifier, "testapp");
+        assert_eq!(sut.attributes, "prerelease=rc");
+        assert_eq!(sut.current_version, "1.0.0");
+
+        let tag = DemverTag::parse(&sut).unwrap();
+        assert_eq!(
+            tag.attributes.prerelease,
+            Some(policy::PrereleasePolicy::Channel("rc".to_owned()))
+        );
+        assert_eq!(
+            tag.format_with_version("1.1.0-rc.1", "2020-12-06T00-00-00"),
+            "[demver(^1.0.0)|file(versions.ini)|testapp|prerelease=rc] 1.1.0-rc.1 @ 2020-12-06T00-00-00"
+        );
+    }
+
+    #[test]
+    fn parse_demver_tag_scheme() {
+        let sut = DemverTag::parse(&TokenizedTag::new(
+            "2024.05",
+            "file(versions.ini)",
+            "testapp",
+            "scheme=calver",
+            "2024.05.01",
+            "2024-05-01T00-00-00",
+            0,
+            0,
+            TEST_FILENAME,
+        ))
+        .unwrap();
+
+        assert_eq!(sut.attributes.scheme, scheme::VersionScheme::Calver);
+        assert_eq!(sut.get_current_version().to_string(), "2024.5.1");
+    }
+
+    #[test]
+    fn parse_demver_tag_variant() {
+        let sut = DemverTag::parse(&TokenizedTag::new(
+            "^1.21",
+            "file(versions.ini)",
+            "golang",
+            "scheme=dotted,variant=alpine",
+            "1.21-alpine",
+            "2024-05-01T00-00-00",
+            0,
+            0,
+            TEST_FILENAME,
+        ))
+        .unwrap();
+
+        assert_eq!(sut.attributes.variant, Some("alpine".to_owned()));
+        assert_eq!(sut.get_current_version().to_string(), "1.21");
+    }
+
+    #[test]
+    fn tokenize_only_comments() {
+        let content = format!("FOO=\"{0}\"\n  {0}\n// {0}\n", &TEST_STRING[2..]);
+        assert!(TokenizedTag::tokenize_all("default.env", &content, 0).is_empty());
+
+        let tags = TokenizedTag::tokenize_all("main.go", &content, 0);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(
+            tags[0].as_ref().unwrap().idx_start,
+            content.find("// ").unwrap() + 3
+        );
+    }
+
+    #[test]
+    fn parse_all_points_at_field() {
+        let content = "\n# [demver(^one)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n\
+                       # [demver(^1.0.0)|ftp(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09\n";
+        let errors: Vec<String> = DemverTag::parse_all(TEST_FILENAME, content)
+            .into_iter()
+            .map(|result| result.unwrap_err().format(content))
+            .collect();
+
+        assert_eq!(errors.len(), 2);
+        assert!(
+            errors[0].starts_with("2:11: bad requirement: "),
+            "{}",
+            errors[0]
+        );
+        assert_eq!(
+            errors[1],
+            "3:19: bad source: unknown version_source tag type 'ftp'"
+        );
+    }
+
+    #[test]
+    fn v2_keeps_fields() {
+        let sut = DemverTag::parse(
+            &TokenizedTag::tokenize_one(
+                TEST_FILENAME,
+                "# [demver:2 req=^1.0.0 src=file(versions.ini) id=testapp variant=alpine next='x y'] 1.0.0-alpine @ 2020-12-05T18-18-09",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(sut.attributes.variant, Some("alpine".to_owned()));
+        assert_eq!(
+            sut.with_version_req(">=1.0.0, <3.0.0")
+                .unwrap()
+                .format_with_version("2.0.0-alpine", "2021-01-02T03-04-05"),
+            "[demver:2 req=\">=1.0.0, <3.0.0\" src=file(versions.ini) id=testapp variant=alpine next='x y'] 2.0.0-alpine @ 2021-01-02T03-04-05"
+        );
+    }
+
+    #[test]
+    fn parse_attributes_invalid() {
+        assert!(TagAttributes::parse("prerelease").is_err());
+        assert!(TagAttributes::parse("foo=bar").is_err());
+        assert!(TagAttributes::parse("scheme=roman").is_err());
+        assert!(TagAttributes::parse("variant=").is_err());
+        assert!(TagAttributes::parse("variant=al pine").is_err());
+        assert!(TagAttributes::parse("group=").is_err());
+        assert_eq!(TagAttributes::parse("").unwrap(), TagAttributes::default());
     }
 
     #[test]
@@ -318,11 +906,85 @@ mod tests {
         assert_eq!(
             file_source,
             SourceTag::File(FileSourceTag {
-                filename: "versions.ini".to_owned()
+                filename: "versions.ini".to_owned(),
+                section: None,
+                format: FileFormat::Ini,
             })
         )
     }
 
+    #[test]
+    fn parse_source_list() {
+        let sources = SourceTag::parse_list("file(a.ini), file(b.ini)").unwrap();
+
+        assert_eq!(
+            sources,
+            [
+                SourceTag::File(FileSourceTag {
+                    filename: "a.ini".to_owned(),
+                    section: None,
+                    format: FileFormat::Ini,
+                }),
+                SourceTag::File(FileSourceTag {
+                    filename: "b.ini".to_owned(),
+                    section: None,
+                    format: FileFormat::Ini,
+                }),
+            ]
+        );
+        assert!(SourceTag::parse_list("file(a.ini),").is_err());
+        assert!(SourceTag::parse_list("file(a.ini),registry(example.com)").is_err());
+    }
+
+    #[test]
+    fn parse_source_arguments() {
+        let sut =
+            SourceTag::parse(r#"file(path="my versions, v2.ini", section='apps\'', format=ini)"#)
+                .unwrap();
+        assert_eq!(
+            sut,
+            SourceTag::File(FileSourceTag {
+                filename: "my versions, v2.ini".to_owned(),
+                section: Some("apps'".to_owned()),
+                format: FileFormat::Ini,
+            })
+        );
+
+        let sut = SourceTag::parse("file(versions.txt, format=ini)").unwrap();
+        assert_eq!(
+            sut,
+            SourceTag::File(FileSourceTag {
+                filename: "versions.txt".to_owned(),
+                section: None,
+                format: FileFormat::Ini,
+            })
+        );
+
+        assert!(SourceTag::parse("file(versions.txt)").is_err());
+        assert!(SourceTag::parse("file(versions.ini, format=toml)").is_err());
+        assert!(SourceTag::parse("file(versions.ini, region=eu)").is_err());
+        assert!(SourceTag::parse("file(versions.ini, other.ini)").is_err());
+        assert!(SourceTag::parse("file(path=a.ini, path=b.ini)").is_err());
+        assert!(SourceTag::parse("file(section=apps, versions.ini)").is_err());
+        assert!(SourceTag::parse(r#"file("versions.ini)"#).is_err());
+        assert!(SourceTag::parse(r#"file("a.ini" b)"#).is_err());
+        assert_eq!(
+            SourceTag::parse_list(r#"file("a),b.ini"), file(b.ini)"#)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn format_source_argument() {
+        assert_eq!(format_argument("../versions.ini"), "../versions.ini");
+        assert_eq!(format_argument(r#"my "v", 2.ini"#), r#""my \"v\", 2.ini""#);
+
+        let arguments = SourceArguments::parse(&format_argument(r#"a\b "c" (d)"#)).unwrap();
+        assert_eq!(arguments.positional, [r#"a\b "c" (d)"#]);
+    }
+
     #[test]
     fn parse_file_source_empty() {
         assert!(SourceTag::parse("file()").is_err());
@@ -0,0 +1,142 @@
+use crate::comment::CommentSyntax;
+use crate::policy::ResolvePolicy;
+use crate::syntax;
+use crate::tag_checker::TagChecker;
+use crate::tag_updater::FileUpdate;
+use crate::target::TargetValue;
+use crate::text_file::TextFile;
+
+/// A tag to be written, with its parts as they are given on the command line.
+#[derive(Debug, Default)]
+pub struct NewTag {
+    pub version_req: String,
+    pub version_source: String,
+    pub identifier: String,
+    pub attributes: String,
+}
+
+/// Inserts a tag above the target line `line_nr` (starting at 1) and pins
+/// the target to the resolved version, without touching the disk.
+pub fn add_tag(
+    filename: &str,
+    line_nr: usize,
+    new_tag: &NewTag,
+    policy: &ResolvePolicy,
+) -> Result<FileUpdate, String> {
+    let text_file = TextFile::read(filename)?;
+    let updated = add_tag_to_content(filename, &text_file, line_nr, new_tag, policy)?;
+
+    Ok(FileUpdate {
+        filename: filename.to_owned(),
+        text_file,
+        updated,
+        tag_update_results: vec![],
+        notes: vec![],
+    })
+}
+
+/// Like `add_tag`, but on a file that was already read.
+pub fn add_tag_to_content(
+    filename: &str,
+    text_file: &TextFile,
+    line_nr: usize,
+    new_tag: &NewTag,
+    policy: &ResolvePolicy,
+) -> Result<String, String> {
+    let content = &text_file.content;
+    let lines: Vec<&str> = content.split('\n').collect();
+    if line_nr == 0
+        || line_nr > lines.len()
+        || (line_nr == lines.len() && lines[line_nr - 1].is_empty())
+    {
+        return Err(format!("{} has no line {}", filename, line_nr));
+    }
+    if line_nr > 1 && lines[line_nr - 2].contains("[demver") {
+        return Err(format!("line {} is already pinned by a tag", line_nr));
+    }
+    let line = lines[line_nr - 1].trim_end_matches('\r');
+    let target = TargetValue::find(line).ok_or_else(|| {
+        format!(
+            "line {} contains no value to pin, expected KEY=VALUE, FROM image or image: image",
+            line_nr
+        )
+    })?;
+
+    let attributes = syntax::TagAttributes::parse(&new_tag.attributes)?;
+    let version_req = attributes.get_format().parse_req(&new_tag.version_req)?;
+    let version_sources = syntax::SourceTag::parse_list(&new_tag.version_source)?;
+    let version = TagChecker::get_version_for_new_tag(
+        filename,
+        &version_req,
+        &version_sources,
+        &new_tag.identifier,
+        &attributes,
+        policy,
+    )?;
+
+    let tag = syntax::TokenizedTag::format(
+        &new_tag.version_req,
+        &new_tag.version_source,
+        &new_tag.identifier,
+        &new_tag.attributes,
+        &version.raw_version,
+        &syntax::format_timestamp(policy.get_now()),
+    );
+    let indentation = &line[..line.len() - line.trim_start().len()];
+    let comment = CommentSyntax::for_filename(filename).format(&tag);
+
+    let line_start: usize = lines[..line_nr - 1].iter().map(|l| l.len() + 1).sum();
+    let line_end = line_start + line.len();
+    Ok(format!(
+        "{}{}{}{}{}{}",
+        &content[..line_start],
+        indentation,
+        comment,
+        text_file.get_line_ending(),
+        target.pin(line, &version.hash),
+        &content[line_end..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_test_tag(filename: &str, line_nr: usize) -> Result<String, String> {
+        let new_tag = NewTag {
+            version_req: "^1.0.0".to_owned(),
+            version_source: "file(versions.ini)".to_owned(),
+            identifier: "testapp".to_owned(),
+            ..NewTag::default()
+        };
+        let now = syntax::parse_timestamp("2021-01-02T03-04-05").unwrap();
+        add_tag(filename, line_nr, &new_tag, &ResolvePolicy::new(now)).map(|u| u.updated)
+    }
+
+    #[test]
+    fn add_to_dockerfile() {
+        assert_eq!(
+            add_test_tag("./test/add/Dockerfile", 1).unwrap(),
+            "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.5.0 @ 2021-01-02T03-04-05\n\
+             FROM testapp@sha256:15 AS build\n\
+             RUN make\n"
+        );
+    }
+
+    #[test]
+    fn add_keeps_indentation_and_line_endings() {
+        assert_eq!(
+            add_test_tag("./test/add/compose.yml", 3).unwrap(),
+            "services:\r\n  app:\r\n    \
+             # [demver(^1.0.0)|file(versions.ini)|testapp] 1.5.0 @ 2021-01-02T03-04-05\r\n    \
+             image: testapp@sha256:15\r\n"
+        );
+    }
+
+    #[test]
+    fn add_invalid_line() {
+        assert!(add_test_tag("./test/add/Dockerfile", 2).is_err());
+        assert!(add_test_tag("./test/add/Dockerfile", 3).is_err());
+        assert!(add_test_tag("./test/add/Dockerfile", 0).is_err());
+    }
+}
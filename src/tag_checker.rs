@@ -1,34 +1,327 @@
 use crate::ini_source;
-use crate::source::VersionSource;
+use crate::policy::ResolvePolicy;
+use crate::scheme;
+use crate::source::{MultiSource, SourceMode, VersionSource};
+use crate::source_cache::CachedSource;
+use crate::source_session;
 use crate::syntax;
 use crate::version;
 
+/// Paths are only canonical if the file exists.
+fn get_canonical_path(filepath: &str) -> String {
+    std::fs::canonicalize(filepath)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| filepath.to_owned())
+}
+
 pub struct TagChecker {}
 
 impl TagChecker {
-    pub fn get_current_version_from_source(
-        tag: &syntax::DemverTag,
-    ) -> Result<version::FixedVersion, String> {
-        match &tag.version_source {
+    fn get_source(tag: &syntax::DemverTag) -> Result<Box<dyn VersionSource>, String> {
+        Self::get_resolving_source(
+            tag.get_origin_filename(),
+            &tag.version_sources,
+            tag.attributes.sources,
+            &tag.version_req,
+            &tag.identifier,
+            Some(tag.get_current_version()),
+        )
+    }
+
+    fn get_source_for(
+        origin_filename: &str,
+        version_sources: &[syntax::SourceTag],
+        mode: SourceMode,
+    ) -> Result<Box<dyn VersionSource>, String> {
+        let mut sources = Self::get_sources(origin_filename, version_sources)?;
+        match sources.len() {
+            1 => Ok(sources.remove(0)),
+            _ => Ok(Box::new(MultiSource::new(sources, mode))),
+        }
+    }
+
+    /// Like `get_source_for`, but a fallback between several sources is
+    /// decided once, so that every lookup for the tag uses the same source.
+    fn get_resolving_source(
+        origin_filename: &str,
+        version_sources: &[syntax::SourceTag],
+        mode: SourceMode,
+        version_req: &scheme::VersionReq,
+        identifier: &str,
+        pinned: Option<&scheme::Version>,
+    ) -> Result<Box<dyn VersionSource>, String> {
+        if version_sources.len() < 2 || mode != SourceMode::Fallback {
+            return Self::get_source_for(origin_filename, version_sources, mode);
+        }
+        let sources = Self::get_sources(origin_filename, version_sources)?;
+        MultiSource::new(sources, mode).into_fallback_source(version_req, Some(identifier), pinned)
+    }
+
+    fn get_sources(
+        origin_filename: &str,
+        version_sources: &[syntax::SourceTag],
+    ) -> Result<Vec<Box<dyn VersionSource>>, String> {
+        version_sources
+            .iter()
+            .map(|source_tag| Self::get_single_source(origin_filename, source_tag))
+            .collect()
+    }
+
+    fn get_single_source(
+        origin_filename: &str,
+        source_tag: &syntax::SourceTag,
+    ) -> Result<Box<dyn VersionSource>, String> {
+        match source_tag {
             syntax::SourceTag::File(file_source_tag) => {
-                if !file_source_tag.filename.ends_with(".ini") {
-                    return Err("File source tags only support .ini files".to_owned());
+                let filepath = Self::get_file_path(origin_filename, file_source_tag)?;
+                let key = format!("file({})", get_canonical_path(&filepath));
+                match file_source_tag.format {
+                    syntax::FileFormat::Ini => {
+                        let file = source_session::get_or_create(&key, || {
+                            ini_source::IniFile::new(&filepath)
+                        });
+                        Ok(CachedSource::wrap(Box::new(
+                            ini_source::IniSource::from_file(file)
+                                .with_section(file_source_tag.section.clone()),
+                        )))
+                    }
                 }
-                let origin_filepath = std::path::Path::new(tag.get_origin_filename());
-                let origin_filepath = match origin_filepath.parent() {
-                    Some(filepath) => filepath,
-                    None => Err("could not open file")?,
-                };
-                let ini_filepath = origin_filepath.join(&file_source_tag.filename);
-                let ini_filepath = match ini_filepath.to_str() {
-                    Some(filepath) => filepath,
-                    None => Err("could not open file")?,
-                };
-                let ini_source = ini_source::IniSource::new(ini_filepath);
-                let fixed_version =
-                    ini_source.get_fixed_version(&tag.semver, Some(&tag.identifier))?;
-                Ok(fixed_version)
             }
         }
     }
+
+    /// File sources are relative to the file containing the tag.
+    fn get_file_path(
+        origin_filename: &str,
+        file_source_tag: &syntax::FileSourceTag,
+    ) -> Result<String, String> {
+        let origin_filepath = std::path::Path::new(origin_filename);
+        let origin_filepath = match origin_filepath.parent() {
+            Some(filepath) => filepath,
+            None => return Err("could not open file".to_owned()),
+        };
+        let filepath = origin_filepath.join(&file_source_tag.filename);
+        match filepath.to_str() {
+            Some(filepath) => Ok(filepath.to_owned()),
+            None => Err("could not open file".to_owned()),
+        }
+    }
+
+    /// The files the sources of a tag are read from.
+    pub fn get_source_files(tag: &syntax::DemverTag) -> Vec<String> {
+        tag.version_sources
+            .iter()
+            .filter_map(|source_tag| match source_tag {
+                syntax::SourceTag::File(file_source_tag) => {
+                    Self::get_file_path(tag.get_origin_filename(), file_source_tag).ok()
+                }
+            })
+            .collect()
+    }
+
+    /// Identifies the versions a tag can pin, regardless of the file it is
+    /// written in: tags with the same key refer to the same thing.
+    pub fn get_source_key(tag: &syntax::DemverTag) -> Result<String, String> {
+        let mut sources = vec![];
+        for source_tag in &tag.version_sources {
+            match source_tag {
+                syntax::SourceTag::File(file_source_tag) => {
+                    let filepath = get_canonical_path(&Self::get_file_path(
+                        tag.get_origin_filename(),
+                        file_source_tag,
+                    )?);
+                    match &file_source_tag.section {
+                        Some(section) => {
+                            sources.push(format!("file({}, section={})", filepath, section))
+                        }
+                        None => sources.push(format!("file({})", filepath)),
+                    }
+                }
+            }
+        }
+
+        let mut key = format!("{}|{}", sources.join(","), tag.identifier);
+        if let Some(variant) = &tag.attributes.variant {
+            key.push_str(&format!("|variant={}", variant));
+        }
+        Ok(key)
+    }
+
+    pub fn get_current_version_from_source(
+        tag: &syntax::DemverTag,
+        policy: &ResolvePolicy,
+    ) -> Result<version::FixedVersion, String> {
+        let source = Self::get_source(tag)?;
+        source.get_fixed_version(
+            &tag.version_req,
+            Some(&tag.identifier),
+            &policy.for_tag(tag),
+        )
+    }
+
+    /// Resolves the version for a tag that is not written yet.
+    pub fn get_version_for_new_tag(
+        origin_filename: &str,
+        version_req: &scheme::VersionReq,
+        version_sources: &[syntax::SourceTag],
+        identifier: &str,
+        attributes: &syntax::TagAttributes,
+        policy: &ResolvePolicy,
+    ) -> Result<version::FixedVersion, String> {
+        let source = Self::get_resolving_source(
+            origin_filename,
+            version_sources,
+            attributes.sources,
+            version_req,
+            identifier,
+            None,
+        )?;
+        source.get_fixed_version(
+            version_req,
+            Some(identifier),
+            &policy.for_attributes(attributes),
+        )
+    }
+
+    /// Returns the newest version the policy allows, ignoring the requirement.
+    pub fn get_newest_version_from_source(
+        tag: &syntax::DemverTag,
+        policy: &ResolvePolicy,
+    ) -> Result<version::FixedVersion, String> {
+        let source = Self::get_source(tag)?;
+        let any_version = tag.version_req.get_format().parse_req("*")?;
+        source.get_fixed_version(&any_version, Some(&tag.identifier), &policy.for_tag(tag))
+    }
+
+    /// Looks up a version explicitly requested for the tag, which has to
+    /// satisfy its requirement.
+    pub fn get_requested_version_from_source(
+        tag: &syntax::DemverTag,
+        raw_version: &str,
+    ) -> Result<version::FixedVersion, String> {
+        if let Some(channel) = tag.version_req.get_channel() {
+            return Err(format!(
+                "tag follows the channel @{}, a version can't be requested",
+                channel
+            ));
+        }
+        let format = tag.version_req.get_format();
+        let requested = format.parse_version(raw_version)?;
+        if !tag.version_req.matches(&requested) {
+            return Err(format!(
+                "{} does not match the requirement {}",
+                raw_version,
+                tag.get_raw_version_req()
+            ));
+        }
+
+        let source = Self::get_source(tag)?;
+        let version = source
+            .get_versions(Some(&tag.identifier), format)?
+            .into_iter()
+            .filter(|v| v.get_version() == &requested)
+            .max()
+            .ok_or_else(|| format!("{} not found in source", raw_version))?;
+        source.verify_version(Some(&tag.identifier), format, &version)?;
+        Ok(version)
+    }
+
+    /// Picks the newest version that every tag allows, and returns it as
+    /// found in the source of each tag, in the order of the tags.
+    pub fn get_common_versions(
+        tags: &[&syntax::DemverTag],
+        policy: &ResolvePolicy,
+    ) -> Result<Vec<version::FixedVersion>, String> {
+        let mut sources = vec![];
+        let mut allowed_versions = vec![];
+        for tag in tags {
+            let source = Self::get_source(tag)?;
+            let format = tag.version_req.get_format();
+            let tag_policy = policy.for_tag(tag);
+            let allowed: Vec<_> = match tag.version_req.get_channel() {
+                Some(_) => vec![source.get_fixed_version(
+                    &tag.version_req,
+                    Some(&tag.identifier),
+                    &tag_policy,
+                )?],
+                None => source
+                    .get_versions(Some(&tag.identifier), format)?
+                    .into_iter()
+                    .filter(|v| tag_policy.matches(&tag.version_req, v))
+                    .collect(),
+            };
+            sources.push(source);
+            allowed_versions.push(allowed);
+        }
+
+        let (first, others) = match allowed_versions.split_first() {
+            Some(split) => split,
+            None => return Ok(vec![]),
+        };
+        let common = first
+            .iter()
+            .filter(|v| {
+                others.iter().all(|allowed| {
+                    allowed
+                        .iter()
+                        .any(|other| other.get_version() == v.get_version())
+                })
+            })
+            .max()
+            .ok_or_else(|| "no version is available for every tag".to_owned())?;
+
+        let mut common_versions = vec![];
+        for ((tag, source), allowed) in tags.iter().zip(&sources).zip(&allowed_versions) {
+            let version = allowed
+                .iter()
+                .filter(|v| v.get_version() == common.get_version())
+                .max()
+                .unwrap()
+                .clone();
+            source.verify_version(
+                Some(&tag.identifier),
+                tag.version_req.get_format(),
+                &version,
+            )?;
+            common_versions.push(version);
+        }
+        Ok(common_versions)
+    }
+
+    /// Lists the identifiers of sources that are not part of a valid tag yet.
+    pub fn get_identifiers_from_source(
+        origin_filename: &str,
+        version_sources: &[syntax::SourceTag],
+    ) -> Result<Vec<String>, String> {
+        Self::get_source_for(origin_filename, version_sources, SourceMode::default())?
+            .get_identifiers()
+    }
+
+    /// Lists the versions of an identifier of sources that are not part of a
+    /// valid tag yet, newest first.
+    pub fn get_versions_from_source(
+        origin_filename: &str,
+        version_sources: &[syntax::SourceTag],
+        identifier: &str,
+        format: &scheme::VersionFormat,
+    ) -> Result<Vec<version::FixedVersion>, String> {
+        let source = Self::get_source_for(origin_filename, version_sources, SourceMode::default())?;
+        let mut versions = source.get_versions(Some(identifier), format)?;
+        versions.sort_by(|a, b| b.cmp(a));
+        Ok(versions)
+    }
+
+    /// Looks up the version the tag is currently pinned to, which is needed
+    /// to find the hash that has to be replaced in the target.
+    pub fn get_pinned_version_from_source(
+        tag: &syntax::DemverTag,
+    ) -> Result<version::FixedVersion, String> {
+        let source = Self::get_source(tag)?;
+        let pinned = tag.get_current_version();
+        source
+            .get_versions(Some(&tag.identifier), tag.version_req.get_format())?
+            .into_iter()
+            .find(|v| v.get_version() == pinned)
+            .ok_or_else(|| format!("pinned version {} not found in source", pinned))
+    }
 }
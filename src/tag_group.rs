@@ -0,0 +1,278 @@
+use crate::syntax;
+use crate::tag_checker::TagChecker;
+use crate::tag_scanner::FileInfo;
+use crate::tag_updater;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A tag together with the file it was found in.
+pub struct TagRef<'a> {
+    pub filename: &'a str,
+    pub content: &'a str,
+    pub tag: &'a syntax::DemverTag,
+}
+
+/// Tags that have to be pinned to the same version.
+pub struct TagGroup<'a> {
+    pub name: String,
+    pub members: Vec<TagRef<'a>>,
+}
+
+impl<'a> TagGroup<'a> {
+    pub fn get_tags(&self) -> Vec<&'a syntax::DemverTag> {
+        self.members.iter().map(|m| m.tag).collect()
+    }
+
+    /// Describes every way in which the members of the group disagree: on
+    /// the pinned version or on the hash in their target.
+    pub fn find_inconsistencies(&self) -> Vec<String> {
+        let mut inconsistencies = vec![];
+
+        let first_version = self.members[0].tag.get_current_version();
+        if self
+            .members
+            .iter()
+            .any(|m| m.tag.get_current_version() != first_version)
+        {
+            let pins: Vec<String> = self
+                .members
+                .iter()
+                .map(|m| format!("{} in {}", m.tag.get_current_version(), m.filename))
+                .collect();
+            inconsistencies.push(format!("pinned to {}", pins.join(", ")));
+        }
+
+        for member in &self.members {
+            let pinned_version = match TagChecker::get_pinned_version_from_source(member.tag) {
+                Ok(v) => v,
+                Err(err_msg) => {
+                    inconsistencies.push(format!("{}: {}", member.filename, err_msg));
+                    continue;
+                }
+            };
+            if let Err(err_msg) =
+                tag_updater::find_pinned_hash(member.content, member.tag, &pinned_version.hash)
+            {
+                inconsistencies.push(format!("{}: {}", member.filename, err_msg));
+            }
+        }
+
+        inconsistencies
+    }
+}
+
+pub fn get_tag_refs(file_infos: &[FileInfo]) -> Vec<TagRef<'_>> {
+    let mut tag_refs = vec![];
+    for file_info in file_infos {
+        if let Ok(file_version) = &file_info.version_result {
+            for tag_version in file_version.tag_version_results.iter().flatten() {
+                tag_refs.push(TagRef {
+                    filename: &file_info.filename,
+                    content: &file_version.text_file.content,
+                    tag: &tag_version.tag,
+                });
+            }
+        }
+    }
+    tag_refs
+}
+
+/// Groups tags pinning the same identifier of the same source across all
+/// files. Only groups with more than one member are returned.
+pub fn group_by_source(file_infos: &[FileInfo]) -> Vec<TagGroup<'_>> {
+    let mut groups: BTreeMap<String, TagGroup> = BTreeMap::new();
+    for tag_ref in get_tag_refs(file_infos) {
+        let key = match TagChecker::get_source_key(tag_ref.tag) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        groups
+            .entry(key)
+            .or_insert_with(|| TagGroup {
+                name: format!(
+                    "{} [{}]",
+                    tag_ref.tag.identifier,
+                    tag_ref.tag.get_raw_source()
+                ),
+                members: vec![],
+            })
+            .members
+            .push(tag_ref);
+    }
+
+    groups
+        .into_values()
+        .filter(|group| group.members.len() > 1)
+        .collect()
+}
+
+/// Groups tags by their `group` attribute across all files.
+pub fn group_by_attribute(file_infos: &[FileInfo]) -> Vec<TagGroup<'_>> {
+    let mut groups: BTreeMap<String, TagGroup> = BTreeMap::new();
+    for tag_ref in get_tag_refs(file_infos) {
+        if let Some(name) = &tag_ref.tag.attributes.group {
+            groups
+                .entry(name.clone())
+                .or_insert_with(|| TagGroup {
+                    name: format!("group {}", name),
+                    members: vec![],
+                })
+                .members
+                .push(tag_ref);
+        }
+    }
+
+    groups.into_values().collect()
+}
+
+/// Merges groups that share a tag, as a tag can only be pinned to one
+/// version.
+pub fn merge_groups(groups: Vec<TagGroup<'_>>) -> Vec<TagGroup<'_>> {
+    let is_same_tag = |a: &TagRef, b: &TagRef| {
+        a.filename == b.filename
+            && a.tag.get_tokenized_tag().get_idx_start()
+                == b.tag.get_tokenized_tag().get_idx_start()
+    };
+
+    let mut merged: Vec<TagGroup> = vec![];
+    for mut group in groups {
+        while let Some(idx) = merged.iter().position(|other| {
+            other
+                .members
+                .iter()
+                .any(|a| group.members.iter().any(|b| is_same_tag(a, b)))
+        }) {
+            let other = merged.remove(idx);
+            group.name = format!("{} + {}", other.name, group.name);
+            for member in other.members {
+                if !group.members.iter().any(|m| is_same_tag(m, &member)) {
+                    group.members.push(member);
+                }
+            }
+        }
+        merged.push(group);
+    }
+    merged
+}
+
+/// Finds the files that must not be written, as a group with members in them
+/// failed to update in some of its files, together with the reason. Files
+/// whose tags are unknown, because they could not be read or contain
+/// malformed tags, may hold members of any group.
+pub fn find_held_back_files(
+    groups: &[TagGroup],
+    failed_files: &BTreeSet<&str>,
+    unknown_files: &BTreeSet<&str>,
+) -> BTreeMap<String, String> {
+    let mut held_back = BTreeMap::new();
+    for group in groups {
+        let mut causes: BTreeSet<&str> = group
+            .members
+            .iter()
+            .map(|m| m.filename)
+            .filter(|filename| failed_files.contains(filename))
+            .collect();
+        causes.extend(unknown_files);
+        if causes.is_empty() {
+            continue;
+        }
+
+        let causes: Vec<&str> = causes.into_iter().collect();
+        let reason = format!("{} failed in {}", group.name, causes.join(", "));
+        for member in &group.members {
+            held_back
+                .entry(member.filename.to_owned())
+                .or_insert_with(|| reason.clone());
+        }
+    }
+    held_back
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag_scanner::TagScanner;
+
+    fn scan(filenames: &[&str]) -> Vec<FileInfo> {
+        TagScanner::new(filenames.iter().map(|f| f.to_string()).collect()).do_scan()
+    }
+
+    #[test]
+    fn group_across_files() {
+        let file_infos = scan(&[
+            "./test/consistent/app.env",
+            "./test/consistent/compose.yml",
+            "./test/update/default.env",
+        ]);
+        let sut = group_by_source(&file_infos);
+
+        // the third file refers to a different versions.ini
+        assert_eq!(sut.len(), 1);
+        assert_eq!(sut[0].name, "testapp [file(versions.ini)]");
+        assert_eq!(sut[0].members.len(), 2);
+    }
+
+    #[test]
+    fn find_inconsistent_pins() {
+        let file_infos = scan(&[
+            "./test/consistent/app.env",
+            "./test/consistent/compose.yml",
+            "./test/consistent/stale.env",
+        ]);
+        let sut = group_by_source(&file_infos);
+        let inconsistencies = sut[0].find_inconsistencies();
+
+        assert_eq!(inconsistencies.len(), 2);
+        assert!(inconsistencies[0].starts_with("pinned to 1.0.0 in ./test/consistent/app.env"));
+        assert!(inconsistencies[1].starts_with("./test/consistent/stale.env: "));
+    }
+
+    #[test]
+    fn group_by_attribute_and_merge() {
+        let file_infos = scan(&[
+            "./test/groups/api.env",
+            "./test/groups/worker.env",
+            "./test/consistent/app.env",
+        ]);
+        let groups = group_by_attribute(&file_infos);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "group app");
+        assert_eq!(groups[0].members.len(), 2);
+
+        let mut all_groups = groups;
+        all_groups.extend(group_by_attribute(&file_infos));
+        let merged = merge_groups(all_groups);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].members.len(), 2);
+    }
+
+    #[test]
+    fn hold_back_failed_groups() {
+        let file_infos = scan(&[
+            "./test/groups/api.env",
+            "./test/groups/broken.env",
+            "./test/consistent/app.env",
+        ]);
+        let groups = group_by_attribute(&file_infos);
+        let none = BTreeSet::new();
+
+        assert!(find_held_back_files(&groups, &none, &none).is_empty());
+
+        let failed = BTreeSet::from(["./test/groups/broken.env"]);
+        let held_back = find_held_back_files(&groups, &failed, &none);
+        assert_eq!(
+            held_back.keys().collect::<Vec<_>>(),
+            ["./test/groups/api.env", "./test/groups/broken.env"]
+        );
+        assert_eq!(
+            held_back["./test/groups/api.env"],
+            "group app failed in ./test/groups/broken.env"
+        );
+
+        // an unreadable file may contain members of any group
+        let unknown = BTreeSet::from(["./test/missing.env"]);
+        let held_back = find_held_back_files(&groups, &unknown, &unknown);
+        assert_eq!(held_back.len(), 2);
+        assert!(!held_back.contains_key("./test/consistent/app.env"));
+    }
+}
@@ -0,0 +1,555 @@
+use crate::comment::CommentSyntax;
+
+const KEYWORD: &str = "demver";
+
+/// A problem with a tag, at a byte offset of the file content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl TagError {
+    pub fn new(offset: usize, message: &str) -> Self {
+        Self {
+            offset,
+            message: message.to_owned(),
+        }
+    }
+
+    /// Renders the error as `line:column: message`.
+    pub fn format(&self, content: &str) -> String {
+        let (line, column) = get_line_column(content, self.offset);
+        format!("{}:{}: {}", line, column, self.message)
+    }
+}
+
+/// Line and column of a byte offset, both starting at 1.
+pub fn get_line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// A part of a tag as written, and where it starts in the file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Field {
+    pub value: String,
+    pub offset: usize,
+}
+
+/// `[demver(req)|source|id|attributes]` or, since version 2,
+/// `[demver:2 req=.. src=.. id=..]` with any number of named fields.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TagSyntax {
+    #[default]
+    V1,
+    V2,
+}
+
+/// A `key=value` field of a version 2 tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedField {
+    pub key: String,
+    /// the value as written, possibly quoted
+    pub raw_value: String,
+    pub value: Field,
+}
+
+/// The fields of a tag, before their content is interpreted.
+#[derive(Debug, Clone, Default)]
+pub struct RawTag {
+    pub syntax: TagSyntax,
+    /// all fields of a version 2 tag in the order they were written
+    pub fields: Vec<NamedField>,
+    pub version_req: Field,
+    pub version_source: Field,
+    pub identifier: Field,
+    pub attributes: Field,
+    pub current_version: Field,
+    pub timestamp: Field,
+    pub idx_start: usize,
+    pub idx_end: usize,
+}
+
+/// Finds all tags within comments. Everything that looks like it was meant
+/// to be a tag, e.g. `[demvr(`, is reported instead of silently skipped.
+pub fn parse_all(content: &str, comment_syntax: &CommentSyntax) -> Vec<Result<RawTag, TagError>> {
+    let mut results = vec![];
+    let mut parsed_until = 0;
+
+    for (idx, _) in content.match_indices('[') {
+        if idx < parsed_until {
+            continue;
+        }
+        let keyword_start = idx + 1;
+        let keyword_len = content[keyword_start..]
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-')
+            .unwrap_or(content.len() - keyword_start);
+        let keyword = &content[keyword_start..keyword_start + keyword_len];
+        let next = content[keyword_start + keyword_len..].chars().next();
+
+        let is_tag = keyword == KEYWORD;
+        let is_near_miss = !is_tag && matches!(next, Some('(') | Some(':')) && is_typo(keyword);
+        if !is_tag && !is_near_miss {
+            continue;
+        }
+
+        let line_start = content[..idx].rfind('\n').map_or(0, |i| i + 1);
+        if !comment_syntax.is_comment_prefix(&content[line_start..idx]) {
+            continue;
+        }
+
+        if is_near_miss {
+            results.push(Err(TagError::new(
+                keyword_start,
+                &format!("unknown keyword '{}', did you mean '{}'?", keyword, KEYWORD),
+            )));
+            continue;
+        }
+
+        let result = parse_tag(content, idx);
+        if let Ok(raw_tag) = &result {
+            parsed_until = raw_tag.idx_end;
+        }
+        results.push(result);
+    }
+
+    results
+}
+
+fn is_typo(keyword: &str) -> bool {
+    let keyword = keyword.to_ascii_lowercase();
+    keyword.len() >= 4 && get_edit_distance(&keyword, KEYWORD) <= 1
+}
+
+/// Levenshtein distance between two words.
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Reads a tag within a single line, starting at its `[`.
+struct Cursor<'a> {
+    content: &'a str,
+    pos: usize,
+    line_end: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.content[self.pos..self.line_end].chars().next()
+    }
+
+    fn expect(&mut self, c: char, message: &str) -> Result<(), TagError> {
+        match self.peek() {
+            Some(next) if next == c => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            _ => Err(TagError::new(self.pos, message)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|c| *c == ' ' || *c == '\t') {
+            self.pos += c.len_utf8();
+        }
+        self.pos - start
+    }
+
+    /// Reads a field up to one of `stops`, which only count outside of
+    /// parentheses and quotes. The stop itself is not consumed.
+    fn read_until(&mut self, stops: &[char], expected: &str) -> Result<(Field, char), TagError> {
+        let start = self.pos;
+        let mut open_parens = vec![];
+        let mut quote = None;
+        let mut escaped = false;
+        while let Some(c) = self.peek() {
+            match (quote, c) {
+                (Some(_), _) if escaped => escaped = false,
+                (Some(_), '\\') => escaped = true,
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (None, '(') => open_parens.push(self.pos),
+                (None, ')') if !open_parens.is_empty() => {
+                    open_parens.pop();
+                }
+                (None, c) if open_parens.is_empty() && stops.contains(&c) => {
+                    return Ok((get_field(self.content, start, self.pos), c));
+                }
+                _ => {}
+            }
+            self.pos += c.len_utf8();
+        }
+        Err(match open_parens.last() {
+            Some(pos) => TagError::new(*pos, "unclosed '('"),
+            None => TagError::new(
+                self.pos,
+                &format!("tag is not terminated, expected {}", expected),
+            ),
+        })
+    }
+
+    /// Reads up to the next whitespace.
+    fn read_word(&mut self) -> Field {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|c| !c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+        get_field(self.content, start, self.pos)
+    }
+}
+
+/// A field with surrounding whitespace trimmed.
+fn get_field(content: &str, start: usize, end: usize) -> Field {
+    let raw = &content[start..end];
+    let offset = start + raw.len() - raw.trim_start().len();
+    Field {
+        value: raw.trim().to_owned(),
+        offset,
+    }
+}
+
+fn non_empty(field: Field, what: &str) -> Result<Field, TagError> {
+    match field.value.as_str() {
+        "" => Err(TagError::new(field.offset, &format!("empty {}", what))),
+        _ => Ok(field),
+    }
+}
+
+/// Removes the quotes around a value and resolves its escapes.
+pub fn unquote(unparsed: &str) -> Result<String, String> {
+    let quote = match unparsed.chars().next() {
+        Some(c) if c == '"' || c == '\'' => c,
+        _ => return Ok(unparsed.to_owned()),
+    };
+
+    let mut value = String::new();
+    let mut chars = unparsed[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next()),
+            c if c == quote => {
+                return match chars.as_str().trim() {
+                    "" => Ok(value),
+                    rest => Err(format!("unexpected '{}' after quoted value", rest)),
+                };
+            }
+            c => value.push(c),
+        }
+    }
+    Err(format!("unterminated quote in {}", unparsed))
+}
+
+/// Quotes the value of a version 2 field if it would not be read back as it is.
+pub fn quote_field(value: &str) -> String {
+    let needs_quotes =
+        |c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ']';
+    match value {
+        v if !v.is_empty() && !v.contains(needs_quotes) => v.to_owned(),
+        v => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+/// `[demver(req)|source|identifier|attributes] version @ timestamp` or
+/// `[demver:2 key=value ...] version @ timestamp`.
+fn parse_tag(content: &str, idx_start: usize) -> Result<RawTag, TagError> {
+    let line_end = content[idx_start..]
+        .find('\n')
+        .map_or(content.len(), |offset| idx_start + offset);
+    let mut cursor = Cursor {
+        content,
+        pos: idx_start + 1 + KEYWORD.len(),
+        line_end,
+    };
+
+    let mut raw_tag = match cursor.peek() {
+        Some(':') => {
+            cursor.pos += 1;
+            let version_start = cursor.pos;
+            while let Some(c) = cursor.peek().filter(char::is_ascii_alphanumeric) {
+                cursor.pos += c.len_utf8();
+            }
+            match &content[version_start..cursor.pos] {
+                "2" => parse_v2_fields(&mut cursor, idx_start)?,
+                "" => return Err(TagError::new(version_start, "expected a version after ':'")),
+                v => {
+                    return Err(TagError::new(
+                        version_start,
+                        &format!("unsupported tag version '{}', expected 2", v),
+                    ))
+                }
+            }
+        }
+        _ => parse_v1_fields(&mut cursor)?,
+    };
+
+    if cursor.skip_whitespace() == 0 {
+        return Err(match cursor.peek() {
+            None => TagError::new(
+                cursor.pos,
+                "missing pinned version and timestamp after the tag",
+            ),
+            Some(_) => TagError::new(cursor.pos, "expected a space after ']'"),
+        });
+    }
+    raw_tag.current_version = non_empty(cursor.read_word(), "pinned version")?;
+    cursor.skip_whitespace();
+    cursor.expect('@', "expected '@' and a timestamp after the pinned version")?;
+    cursor.skip_whitespace();
+    raw_tag.timestamp = cursor.read_word();
+    if raw_tag.timestamp.value.is_empty() {
+        return Err(TagError::new(cursor.pos, "missing timestamp after '@'"));
+    }
+
+    raw_tag.idx_start = idx_start;
+    raw_tag.idx_end = cursor.pos;
+    Ok(raw_tag)
+}
+
+/// `key=value` fields separated by whitespace, up to the closing `]`.
+fn parse_v2_fields(cursor: &mut Cursor, idx_start: usize) -> Result<RawTag, TagError> {
+    let mut raw_tag = RawTag {
+        syntax: TagSyntax::V2,
+        ..RawTag::default()
+    };
+
+    if !matches!(cursor.peek(), Some(' ') | Some('\t')) {
+        return Err(TagError::new(
+            cursor.pos,
+            "expected a space after '[demver:2'",
+        ));
+    }
+    loop {
+        cursor.skip_whitespace();
+        if cursor.peek() == Some(']') {
+            cursor.pos += 1;
+            break;
+        }
+        let (field, _) = cursor.read_until(&[' ', '\t', ']'], "']' after the fields")?;
+        let (key, raw_value) = match field.value.split_once('=') {
+            Some(key_value) => key_value,
+            None => {
+                return Err(TagError::new(
+                    field.offset,
+                    &format!("expected 'key=value', found '{}'", field.value),
+                ))
+            }
+        };
+        let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if key.is_empty() || !key.chars().all(is_name) {
+            return Err(TagError::new(
+                field.offset,
+                &format!("invalid field name '{}'", key),
+            ));
+        }
+        if raw_tag.fields.iter().any(|f| f.key == key) {
+            return Err(TagError::new(
+                field.offset,
+                &format!("field '{}' is given twice", key),
+            ));
+        }
+        let value_offset = field.offset + key.len() + 1;
+        let value = Field {
+            value: unquote(raw_value).map_err(|e| TagError::new(value_offset, &e))?,
+            offset: value_offset,
+        };
+        let value = non_empty(value, &format!("value of field '{}'", key))?;
+
+        match key {
+            "req" => raw_tag.version_req = value.clone(),
+            "src" => raw_tag.version_source = value.clone(),
+            "id" => raw_tag.identifier = value.clone(),
+            _ => {}
+        }
+        raw_tag.fields.push(NamedField {
+            key: key.to_owned(),
+            raw_value: raw_value.to_owned(),
+            value,
+        });
+    }
+
+    for key in &["req", "src", "id"] {
+        if !raw_tag.fields.iter().any(|f| f.key == *key) {
+            return Err(TagError::new(
+                idx_start,
+                &format!("missing field '{}'", key),
+            ));
+        }
+    }
+    raw_tag.attributes.offset = cursor.pos - 1;
+    Ok(raw_tag)
+}
+
+/// `(req)|source|identifier|attributes]`, where attributes are optional.
+fn parse_v1_fields(cursor: &mut Cursor) -> Result<RawTag, TagError> {
+    cursor.expect('(', "expected '(' or ':2' after '[demver'")?;
+    let (version_req, _) = cursor.read_until(&[')'], "')' after the requirement")?;
+    let version_req = non_empty(version_req, "requirement")?;
+    cursor.pos += 1;
+    cursor.skip_whitespace();
+    cursor.expect('|', "expected '|' after the requirement")?;
+
+    let (version_source, stop) = cursor.read_until(&['|', ']'], "'|' after the source")?;
+    let version_source = non_empty(version_source, "source")?;
+    if stop == ']' {
+        return Err(TagError::new(
+            cursor.pos,
+            "expected '|' and an identifier after the source",
+        ));
+    }
+    cursor.pos += 1;
+
+    let (identifier, stop) = cursor.read_until(&['|', ']'], "']' after the identifier")?;
+    let identifier = non_empty(identifier, "identifier")?;
+    cursor.pos += 1;
+    let attributes = match stop {
+        '|' => {
+            let (attributes, _) = cursor.read_until(&[']'], "']' after the attributes")?;
+            cursor.pos += 1;
+            non_empty(attributes, "attributes")?
+        }
+        _ => Field {
+            value: String::new(),
+            offset: cursor.pos - 1,
+        },
+    };
+
+    Ok(RawTag {
+        version_req,
+        version_source,
+        identifier,
+        attributes,
+        ..RawTag::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(line: &str) -> Result<RawTag, String> {
+        let comment_syntax = CommentSyntax::for_filename("default.env");
+        let mut results = parse_all(line, &comment_syntax);
+        assert_eq!(results.len(), 1, "{:?}", results);
+        results.remove(0).map_err(|e| e.format(line))
+    }
+
+    #[test]
+    fn parse_tolerates_spaces() {
+        let sut = parse_one(
+            "# [demver( ^1.0.0 ) | file(a.ini, b.ini) | testapp]  1.0.0 @  2020-12-05T18-18-09",
+        )
+        .unwrap();
+
+        assert_eq!(sut.version_req.value, "^1.0.0");
+        assert_eq!(sut.version_req.offset, 11);
+        assert_eq!(sut.version_source.value, "file(a.ini, b.ini)");
+        assert_eq!(sut.identifier.value, "testapp");
+        assert_eq!(sut.attributes.value, "");
+        assert_eq!(sut.current_version.value, "1.0.0");
+        assert_eq!(sut.timestamp.value, "2020-12-05T18-18-09");
+    }
+
+    #[test]
+    fn parse_diagnostics() {
+        let err = |line| parse_one(line).unwrap_err();
+
+        assert_eq!(
+            err("# [demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0"),
+            "1:52: expected '@' and a timestamp after the pinned version"
+        );
+        assert_eq!(
+            err("# [demver(^1.0.0)|file(versions.ini)|testapp]"),
+            "1:46: missing pinned version and timestamp after the tag"
+        );
+        assert_eq!(
+            err("# [demver(^1.0.0)|file(versions.ini)] 1.0.0 @ 2020-12-05T18-18-09"),
+            "1:37: expected '|' and an identifier after the source"
+        );
+        assert_eq!(
+            err("# [demver(^1.0.0) file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09"),
+            "1:19: expected '|' after the requirement"
+        );
+        assert_eq!(
+            err("# [demver(^1.0.0)||testapp] 1.0.0 @ 2020-12-05T18-18-09"),
+            "1:19: empty source"
+        );
+        assert_eq!(
+            err("# [demver(^1.0.0)|file(versions.ini|testapp] 1.0.0 @ 2020"),
+            "1:23: unclosed '('"
+        );
+        assert_eq!(
+            err("# [demvr(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09"),
+            "1:4: unknown keyword 'demvr', did you mean 'demver'?"
+        );
+        assert_eq!(
+            err("# [Demver(^1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2020-12-05T18-18-09"),
+            "1:4: unknown keyword 'Demver', did you mean 'demver'?"
+        );
+    }
+
+    #[test]
+    fn parse_v2() {
+        let sut = parse_one(
+            "# [demver:2 id=testapp req=\">=1.0, <2\" src=file(a.ini) new=\"x ]\"] 1.0.0 @ 2020-12-05T18-18-09",
+        )
+        .unwrap();
+
+        assert_eq!(sut.syntax, TagSyntax::V2);
+        assert_eq!(sut.version_req.value, ">=1.0, <2");
+        assert_eq!(sut.version_req.offset, 27);
+        assert_eq!(sut.version_source.value, "file(a.ini)");
+        assert_eq!(sut.identifier.value, "testapp");
+        assert_eq!(sut.fields[3].key, "new");
+        assert_eq!(sut.fields[3].raw_value, "\"x ]\"");
+        assert_eq!(sut.current_version.value, "1.0.0");
+
+        let err = |line| parse_one(line).unwrap_err();
+        assert_eq!(
+            err("# [demver:2 req=^1 src=file(a.ini)] 1.0.0 @ 2020"),
+            "1:3: missing field 'id'"
+        );
+        assert_eq!(
+            err("# [demver:2 req=^1 src=file(a.ini) id] 1.0.0 @ 2020"),
+            "1:36: expected 'key=value', found 'id'"
+        );
+        assert_eq!(
+            err("# [demver:2 req=^1 req=^2 src=file(a.ini) id=x] 1.0.0 @ 2020"),
+            "1:20: field 'req' is given twice"
+        );
+        assert_eq!(
+            err("# [demver:3 req=^1] 1.0.0 @ 2020"),
+            "1:11: unsupported tag version '3', expected 2"
+        );
+    }
+
+    #[test]
+    fn parse_ignores_unrelated_brackets() {
+        let comment_syntax = CommentSyntax::for_filename("default.env");
+
+        assert!(parse_all("# [denver] [x] [dem(", &comment_syntax).is_empty());
+        assert!(parse_all("X=\"[demvr(\"", &comment_syntax).is_empty());
+    }
+
+    #[test]
+    fn line_column() {
+        assert_eq!(get_line_column("ab\ncäd", 6), (2, 3));
+        assert_eq!(get_line_column("", 0), (1, 1));
+    }
+}
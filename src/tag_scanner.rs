@@ -1,6 +1,6 @@
 use crate::syntax;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::text_file::TextFile;
+use rayon::prelude::*;
 
 #[derive(Debug)]
 pub struct TagScanner {
@@ -18,6 +18,7 @@ pub type TagVersionResult = Result<TagVersion, String>;
 
 #[derive(Debug)]
 pub struct FileVersion {
+    pub text_file: TextFile,
     pub tag_version_results: Vec<TagVersionResult>,
 }
 
@@ -35,44 +36,85 @@ impl TagScanner {
     }
 
     fn scan_file(&self, filename: &str) -> FileVersionResult {
-        let mut file = File::open(filename).or(Err("failed to open file"))?;
-        let mut file_content = String::new();
-        file.read_to_string(&mut file_content)
-            .or(Err("failed to read file as a string"))?;
-
-        let tokenized_tags = syntax::TokenizedTag::tokenize_all(filename, &file_content, 0);
-        let version_results: Vec<TagVersionResult> = tokenized_tags
-            .into_iter()
-            .map(|tt| match tt {
-                Ok(tag) => match syntax::DemverTag::parse(&tag) {
-                    Ok(demver_tag) => TagVersionResult::Ok(TagVersion { tag: demver_tag }),
-                    Err(err_str) => TagVersionResult::Err(format!(
-                        "failed to parse tag in file {}: {}",
-                        filename, err_str
-                    )),
-                },
-                Err(err_str) => TagVersionResult::Err(format!(
-                    "failed to tokenize tag in file {}: {}",
-                    filename, err_str
-                )),
-            })
-            .collect();
+        let text_file = TextFile::read(filename)?;
+
+        let version_results: Vec<TagVersionResult> =
+            syntax::DemverTag::parse_all(filename, &text_file.content)
+                .into_iter()
+                .map(|result| match result {
+                    Ok(tag) => Ok(TagVersion { tag }),
+                    Err(err) => Err(format!("{}:{}", filename, err.format(&text_file.content))),
+                })
+                .collect();
 
         Ok(FileVersion {
+            text_file,
             tag_version_results: version_results,
         })
     }
 
+    /// Files are scanned concurrently, the result is in the order of the
+    /// files.
     pub fn do_scan(&self) -> Vec<FileInfo> {
-        let mut ret: Vec<FileInfo> = vec![];
-
-        for file in &self.files {
-            ret.push(FileInfo {
+        self.files
+            .par_iter()
+            .map(|file| FileInfo {
                 filename: file.to_owned(),
-                version_result: self.scan_file(&file),
-            });
-        }
+                version_result: self.scan_file(file),
+            })
+            .collect()
+    }
+}
+
+/// Calls `f` for all tags of the scanned files concurrently. The results are
+/// in the order of the files and of the tags within them, files that could
+/// not be read have none.
+pub fn map_tags<T: Send>(
+    file_infos: &[FileInfo],
+    f: impl Fn(&TagVersionResult) -> T + Sync,
+) -> Vec<Vec<T>> {
+    file_infos
+        .par_iter()
+        .map(|file_info| match &file_info.version_result {
+            Ok(file_version) => file_version
+                .tag_version_results
+                .par_iter()
+                .map(&f)
+                .collect(),
+            Err(_) => vec![],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_ordered() {
+        let files: Vec<String> = [
+            "./test/simple/default.env",
+            "./test/missing.env",
+            "./test/groups/api.env",
+            "./test/groups/worker.env",
+        ]
+        .iter()
+        .map(|file| file.to_string())
+        .collect();
+        let file_infos = TagScanner::new(files.clone()).do_scan();
+
+        let filenames: Vec<_> = file_infos
+            .iter()
+            .map(|info| info.filename.clone())
+            .collect();
+        assert_eq!(filenames, files);
 
-        ret
+        let identifiers = map_tags(&file_infos, |result| {
+            result.as_ref().unwrap().tag.identifier.clone()
+        });
+        assert_eq!(
+            identifiers,
+            [vec!["testapp"], vec![], vec!["app-api"], vec!["app-worker"]]
+        );
     }
 }
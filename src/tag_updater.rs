@@ -0,0 +1,646 @@
+use crate::policy::ResolvePolicy;
+use crate::syntax;
+use crate::tag_checker::TagChecker;
+use crate::tag_group::TagGroup;
+use crate::tag_scanner::FileInfo;
+use crate::text_file::TextFile;
+use crate::version::FixedVersion;
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct TagUpdate {
+    pub identifier: String,
+    pub old_version_req: String,
+    pub new_version_req: String,
+    pub old_version: String,
+    pub old_timestamp: String,
+    pub old_age: chrono::Duration,
+    pub new_version: String,
+}
+
+/// `Ok(None)` means the tag is already pinned to the newest version.
+pub type TagUpdateResult = Result<Option<TagUpdate>, String>;
+
+#[derive(Debug)]
+pub struct FileUpdate {
+    pub filename: String,
+    pub text_file: TextFile,
+    pub updated: String,
+    pub tag_update_results: Vec<TagUpdateResult>,
+    /// Tags that keep their pin although newer versions match, because none
+    /// of them is old enough yet.
+    pub notes: Vec<String>,
+}
+
+impl FileUpdate {
+    pub fn has_changes(&self) -> bool {
+        self.text_file.content != self.updated
+    }
+
+    pub fn unified_diff(&self) -> String {
+        similar::TextDiff::from_lines(&self.text_file.content, &self.updated)
+            .unified_diff()
+            .header(&self.filename, &self.filename)
+            .to_string()
+    }
+
+    pub fn write(&self) -> Result<(), String> {
+        self.text_file
+            .with_content(self.updated.clone())
+            .write(&self.filename)
+    }
+}
+
+/// Replaces a byte range of the file content.
+#[derive(Debug)]
+pub struct Edit {
+    pub idx_start: usize,
+    pub idx_end: usize,
+    pub replacement: String,
+}
+
+/// Tags are identified by their file and position.
+type TagKey = (String, usize);
+
+fn get_tag_key(tag: &syntax::DemverTag) -> TagKey {
+    (
+        tag.get_origin_filename().clone(),
+        tag.get_tokenized_tag().get_idx_start(),
+    )
+}
+
+/// Rewrites the requirement of a tag before it is resolved.
+#[derive(Debug)]
+pub enum RangeBump {
+    /// Widen the requirement to the release line of the newest version, if
+    /// that is outside of it.
+    Newest,
+    /// Replace the requirement.
+    To(String),
+}
+
+/// Which tags to update, and to which version.
+#[derive(Debug, Default)]
+pub struct UpdateOptions {
+    /// Identifiers of the tags to update, all tags if empty.
+    pub only: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Pin this version instead of the newest allowed one.
+    pub to_version: Option<String>,
+    pub bump_range: Option<RangeBump>,
+}
+
+impl UpdateOptions {
+    fn is_selected(&self, tag: &syntax::DemverTag) -> bool {
+        (self.only.is_empty() || self.only.contains(&tag.identifier))
+            && !self.exclude.contains(&tag.identifier)
+    }
+
+    /// A version or requirement given explicitly may move a pin backwards.
+    fn is_explicit(&self) -> bool {
+        self.to_version.is_some() || matches!(self.bump_range, Some(RangeBump::To(_)))
+    }
+}
+
+pub struct TagUpdater {
+    policy: ResolvePolicy,
+    options: UpdateOptions,
+    timestamp: String,
+    group_versions: HashMap<TagKey, Result<FixedVersion, String>>,
+}
+
+impl TagUpdater {
+    pub fn new(policy: ResolvePolicy, options: UpdateOptions) -> Self {
+        let timestamp = syntax::format_timestamp(policy.get_now());
+        Self {
+            policy,
+            options,
+            timestamp,
+            group_versions: HashMap::new(),
+        }
+    }
+
+    /// Resolves a common version for the members of each group, which is
+    /// used instead of resolving the members one by one. If any member can't
+    /// be updated, none of them is.
+    pub fn with_groups(mut self, groups: &[TagGroup]) -> Self {
+        for group in groups {
+            let tags = group.get_tags();
+            if !tags.iter().any(|tag| self.options.is_selected(tag)) {
+                continue;
+            }
+            match self.resolve_group(group) {
+                Ok(versions) => {
+                    for (tag, version) in tags.iter().zip(versions) {
+                        self.group_versions.insert(get_tag_key(tag), Ok(version));
+                    }
+                }
+                Err(err_msg) => {
+                    for tag in &tags {
+                        let err_msg = format!("{}: {}", group.name, err_msg);
+                        self.group_versions.insert(get_tag_key(tag), Err(err_msg));
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    fn resolve_group(&self, group: &TagGroup) -> Result<Vec<FixedVersion>, String> {
+        for member in &group.members {
+            if !self.options.is_selected(member.tag) {
+                return Err(format!(
+                    "{} in {} is not selected for the update",
+                    member.tag.identifier, member.filename
+                ));
+            }
+            let pinned_version = TagChecker::get_pinned_version_from_source(member.tag)
+                .map_err(|err_msg| format!("{}: {}", member.filename, err_msg))?;
+            find_pinned_hash(member.content, member.tag, &pinned_version.hash)
+                .map_err(|err_msg| format!("{}: {}", member.filename, err_msg))?;
+        }
+
+        let tags = group.get_tags();
+        let bumped_tags = tags
+            .iter()
+            .map(|tag| self.bump_range(tag))
+            .collect::<Result<Vec<_>, String>>()?;
+        let tags: Vec<&syntax::DemverTag> = tags
+            .iter()
+            .zip(&bumped_tags)
+            .map(|(tag, bumped_tag)| bumped_tag.as_ref().unwrap_or(tag))
+            .collect();
+        let versions = match &self.options.to_version {
+            Some(to_version) => tags
+                .iter()
+                .map(|tag| TagChecker::get_requested_version_from_source(tag, to_version))
+                .collect::<Result<Vec<_>, String>>()?,
+            None => TagChecker::get_common_versions(&tags, &self.policy)?,
+        };
+        for (tag, version) in tags.iter().zip(&versions) {
+            if tag.version_req.get_channel().is_none()
+                && !self.options.is_explicit()
+                && version.get_version() < tag.get_current_version()
+            {
+                return Err(format!(
+                    "common version {} is older than {} pinned in {}",
+                    version.raw_version,
+                    tag.get_current_version(),
+                    tag.get_origin_filename()
+                ));
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Applies the range bump to the tag. `Ok(None)` means the tag keeps its
+    /// requirement.
+    fn bump_range(&self, tag: &syntax::DemverTag) -> Result<Option<syntax::DemverTag>, String> {
+        let version_req = match &self.options.bump_range {
+            None => return Ok(None),
+            Some(RangeBump::To(version_req)) => version_req.clone(),
+            Some(RangeBump::Newest) => {
+                if tag.version_req.get_channel().is_some() {
+                    return Ok(None);
+                }
+                let newest = TagChecker::get_newest_version_from_source(tag, &self.policy)?;
+                if tag.version_req.matches(newest.get_version()) {
+                    return Ok(None);
+                }
+                newest.get_version().get_caret_req()
+            }
+        };
+        tag.with_version_req(&version_req).map(Some)
+    }
+
+    /// Computes the new content of a scanned file without touching the disk.
+    pub fn update_file(&self, file_info: &FileInfo) -> Result<FileUpdate, String> {
+        let file_version = file_info.version_result.as_ref()?;
+        let content = &file_version.text_file.content;
+
+        let mut edits: Vec<Edit> = vec![];
+        let mut tag_update_results: Vec<TagUpdateResult> = vec![];
+        let mut notes: Vec<String> = vec![];
+
+        for tag_version_result in &file_version.tag_version_results {
+            let tag_update_result = match tag_version_result {
+                Ok(tag_version) => self.update_tag(&tag_version.tag, content, &mut edits),
+                Err(err_msg) => Err(err_msg.clone()),
+            };
+            if let (Ok(None), Ok(tag_version)) = (&tag_update_result, tag_version_result) {
+                notes.extend(self.find_ineligible_update(&tag_version.tag));
+            }
+            tag_update_results.push(tag_update_result);
+        }
+
+        let mut updated = content.clone();
+        edits.sort_by_key(|e| e.idx_start);
+        for edit in edits.iter().rev() {
+            updated.replace_range(edit.idx_start..edit.idx_end, &edit.replacement);
+        }
+
+        Ok(FileUpdate {
+            filename: file_info.filename.clone(),
+            text_file: file_version.text_file.clone(),
+            updated,
+            tag_update_results,
+            notes,
+        })
+    }
+
+    /// Reports a tag that keeps its pin only because newer matching versions
+    /// are younger than the minimum age.
+    fn find_ineligible_update(&self, tag: &syntax::DemverTag) -> Option<String> {
+        let min_age = self.policy.get_min_age()?;
+        if !self.options.is_selected(tag) || self.options.to_version.is_some() {
+            return None;
+        }
+        let any_age = self.policy.clone().with_min_age(None);
+        let newest = TagChecker::get_current_version_from_source(tag, &any_age).ok()?;
+        if newest.get_version() <= tag.get_current_version() {
+            return None;
+        }
+        Some(format!(
+            "{} no eligible update, {} is not older than {}",
+            tag.identifier,
+            newest.raw_version,
+            crate::age::format_age(min_age)
+        ))
+    }
+
+    /// Adds the edits for updating a single tag and its target.
+    pub fn update_tag(
+        &self,
+        tag: &syntax::DemverTag,
+        content: &str,
+        edits: &mut Vec<Edit>,
+    ) -> TagUpdateResult {
+        if !self.options.is_selected(tag) {
+            return Ok(None);
+        }
+        let old_version_req = tag.get_raw_version_req().clone();
+        let bumped_tag = self.bump_range(tag)?;
+        let tag = bumped_tag.as_ref().unwrap_or(tag);
+        let is_range_bumped = tag.get_raw_version_req() != &old_version_req;
+
+        let pinned_version = TagChecker::get_pinned_version_from_source(tag)?;
+        let new_version = match (
+            self.group_versions.get(&get_tag_key(tag)),
+            &self.options.to_version,
+        ) {
+            (Some(group_version), _) => group_version.clone()?,
+            (None, Some(to_version)) => {
+                TagChecker::get_requested_version_from_source(tag, to_version)?
+            }
+            (None, None) => TagChecker::get_current_version_from_source(tag, &self.policy)?,
+        };
+
+        // the policy may hide the pinned version, which must not cause a
+        // downgrade, unless a channel was moved back or the version was
+        // requested explicitly
+        let is_downgrade = new_version < pinned_version
+            && tag.version_req.get_channel().is_none()
+            && !self.options.is_explicit();
+        let is_unchanged = new_version == pinned_version
+            && new_version.get_hash() == pinned_version.get_hash()
+            && !is_range_bumped;
+        if is_downgrade || is_unchanged {
+            return Ok(None);
+        }
+
+        let tokenized_tag = tag.get_tokenized_tag();
+        let hash_offset = find_pinned_hash(content, tag, &pinned_version.hash)?;
+
+        edits.push(Edit {
+            idx_start: tokenized_tag.get_idx_start(),
+            idx_end: tokenized_tag.get_idx_end(),
+            replacement: tag.format_with_version(&new_version.raw_version, &self.timestamp),
+        });
+        edits.push(Edit {
+            idx_start: hash_offset,
+            idx_end: hash_offset + pinned_version.hash.len(),
+            replacement: new_version.hash.clone(),
+        });
+
+        Ok(Some(TagUpdate {
+            identifier: tag.identifier.clone(),
+            old_version_req,
+            new_version_req: tag.get_raw_version_req().clone(),
+            old_version: pinned_version.raw_version,
+            old_timestamp: tag.get_raw_timestamp().clone(),
+            old_age: tag.get_age(self.policy.get_now()),
+            new_version: new_version.raw_version,
+        }))
+    }
+}
+
+/// Returns the offset of the pinned hash within the target of the tag.
+pub fn find_pinned_hash(
+    content: &str,
+    tag: &syntax::DemverTag,
+    hash: &str,
+) -> Result<usize, String> {
+    let tag_end = tag.get_tokenized_tag().get_idx_end();
+    let (target_start, target_end) = find_target_line(content, tag_end)?;
+    match find_hash(&content[target_start..target_end], hash) {
+        Some(offset) => Ok(target_start + offset),
+        None => Err(format!(
+            "the line following the tag for {} does not contain the pinned hash {}",
+            tag.identifier, hash
+        )),
+    }
+}
+
+/// The target of a tag is the line directly following it.
+fn find_target_line(content: &str, tag_end: usize) -> Result<(usize, usize), String> {
+    let start = match content[tag_end..].find('\n') {
+        Some(offset) => tag_end + offset + 1,
+        None => return Err("no target line found after tag".to_owned()),
+    };
+    let end = match content[start..].find('\n') {
+        Some(offset) => start + offset,
+        None => content.len(),
+    };
+    Ok((start, end))
+}
+
+/// Finds the hash as a whole token, so that a short hash is never matched
+/// inside of a longer one.
+fn find_hash(line: &str, hash: &str) -> Option<usize> {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric();
+    line.match_indices(hash).map(|(idx, _)| idx).find(|&idx| {
+        let before = line[..idx].chars().next_back();
+        let after = line[idx + hash.len()..].chars().next();
+        !before.is_some_and(is_token_char) && !after.is_some_and(is_token_char)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag_scanner::TagScanner;
+
+    const TEST_TIMESTAMP: &str = "2021-01-02T03-04-05";
+
+    fn update_test_file(filename: &str) -> FileUpdate {
+        update_test_file_with(filename, UpdateOptions::default())
+    }
+
+    fn update_test_file_with(filename: &str, options: UpdateOptions) -> FileUpdate {
+        let now = syntax::parse_timestamp(TEST_TIMESTAMP).unwrap();
+        update_test_file_with_policy(filename, options, ResolvePolicy::new(now))
+    }
+
+    fn update_test_file_with_policy(
+        filename: &str,
+        options: UpdateOptions,
+        policy: ResolvePolicy,
+    ) -> FileUpdate {
+        let scanner = TagScanner::new(vec![filename.to_owned()]);
+        let file_infos = scanner.do_scan();
+        let updater = TagUpdater::new(policy, options);
+        updater.update_file(&file_infos[0]).unwrap()
+    }
+
+    #[test]
+    fn update_rewrites_tag_and_target() {
+        let sut = update_test_file("./test/update/default.env");
+
+        assert!(sut.has_changes());
+        assert_eq!(
+            sut.updated,
+            "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.5.0 @ 2021-01-02T03-04-05\n\
+             TEST_VERSION=sha256:15\n"
+        );
+        let tag_update = sut.tag_update_results[0]
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap();
+        assert_eq!(tag_update.old_version, "1.0.0");
+        assert_eq!(tag_update.old_timestamp, "2020-12-05T18-18-09");
+        assert_eq!(tag_update.old_age.num_days(), 27);
+        assert_eq!(tag_update.new_version, "1.5.0");
+    }
+
+    #[test]
+    fn update_diff_contains_changes() {
+        let sut = update_test_file("./test/update/default.env");
+        let diff = sut.unified_diff();
+
+        assert!(diff.contains("-TEST_VERSION=sha256:1\n"));
+        assert!(diff.contains("+TEST_VERSION=sha256:15\n"));
+    }
+
+    #[test]
+    fn update_keeps_line_endings() {
+        let sut = update_test_file("./test/update/crlf.env");
+
+        assert_eq!(
+            sut.updated,
+            "# [demver(^1.0.0)|file(versions.ini)|testapp] 1.5.0 @ 2021-01-02T03-04-05\r\n\
+             TEST_VERSION=sha256:15"
+        );
+    }
+
+    #[test]
+    fn update_keeps_pin_without_eligible_version() {
+        let now = syntax::parse_timestamp(TEST_TIMESTAMP).unwrap();
+        let policy = ResolvePolicy::new(now).with_min_age(Some(chrono::Duration::days(3)));
+
+        // the source has no release dates at all
+        let sut = update_test_file_with_policy(
+            "./test/update/default.env",
+            UpdateOptions::default(),
+            policy.clone(),
+        );
+        assert!(!sut.has_changes());
+        assert!(sut.tag_update_results[0].as_ref().unwrap().is_none());
+        assert_eq!(
+            sut.notes,
+            ["testapp no eligible update, 1.5.0 is not older than 3d"]
+        );
+
+        // the newest version is too young, the pin is the newest old enough
+        let now = syntax::parse_timestamp("2020-12-05T00-00-00").unwrap();
+        let policy = ResolvePolicy::new(now).with_min_age(Some(chrono::Duration::days(3)));
+        let sut = update_test_file_with_policy(
+            "./test/cooldown/pinned.env",
+            UpdateOptions::default(),
+            policy,
+        );
+        assert!(!sut.has_changes());
+        assert_eq!(
+            sut.notes,
+            ["testapp no eligible update, 1.6.0 is not older than 3d"]
+        );
+    }
+
+    #[test]
+    fn update_with_fallback_sources() {
+        let sut = update_test_file("./test/multi/fallback.env");
+
+        assert_eq!(
+            sut.updated,
+            "# [demver(^1.0.0)|file(outdated.ini),file(primary.ini)|testapp] 1.6.0 @ 2021-01-02T03-04-05\n\
+             TEST_VERSION=sha256:16\n"
+        );
+    }
+
+    #[test]
+    fn update_follows_channel() {
+        let sut = update_test_file("./test/channels/default.env");
+
+        assert_eq!(
+            sut.updated,
+            "# [demver(@stable)|file(versions.ini)|testapp] 1.5.0 @ 2021-01-02T03-04-05\n\
+             TEST_VERSION=sha256:15\n"
+        );
+    }
+
+    #[test]
+    fn update_selection() {
+        let sut = update_test_file_with(
+            "./test/update/default.env",
+            UpdateOptions {
+                exclude: vec!["testapp".to_owned()],
+                ..UpdateOptions::default()
+            },
+        );
+        assert!(!sut.has_changes());
+
+        let sut = update_test_file_with(
+            "./test/update/default.env",
+            UpdateOptions {
+                only: vec!["testapp".to_owned()],
+                ..UpdateOptions::default()
+            },
+        );
+        assert!(sut.has_changes());
+    }
+
+    #[test]
+    fn update_to_version() {
+        let to_version = |v: &str| UpdateOptions {
+            to_version: Some(v.to_owned()),
+            ..UpdateOptions::default()
+        };
+
+        let sut = update_test_file_with("./test/update/default.env", to_version("1.0.0"));
+        assert!(!sut.has_changes());
+
+        // downgrades are fine when asked for explicitly
+        let sut = update_test_file_with("./test/consistent/compose.yml", to_version("1.0.0"));
+        assert!(sut.updated.contains("image: testapp@sha256:1\n"));
+
+        let sut = update_test_file_with("./test/update/default.env", to_version("2.0.0"));
+        assert!(sut.tag_update_results[0]
+            .as_ref()
+            .unwrap_err()
+            .contains("does not match the requirement"));
+
+        let sut = update_test_file_with("./test/update/default.env", to_version("1.2.0"));
+        assert!(sut.tag_update_results[0]
+            .as_ref()
+            .unwrap_err()
+            .contains("not found in source"));
+
+        let sut = update_test_file_with("./test/channels/default.env", to_version("1.0.0"));
+        assert!(sut.tag_update_results[0]
+            .as_ref()
+            .unwrap_err()
+            .contains("channel"));
+    }
+
+    #[test]
+    fn update_bump_range() {
+        let bump_range = |bump_range| UpdateOptions {
+            bump_range: Some(bump_range),
+            ..UpdateOptions::default()
+        };
+
+        let sut = update_test_file_with("./test/update/default.env", bump_range(RangeBump::Newest));
+        assert_eq!(
+            sut.updated,
+            "# [demver(^2.0.0)|file(versions.ini)|testapp] 2.0.0 @ 2021-01-02T03-04-05\n\
+             TEST_VERSION=sha256:2\n"
+        );
+        let tag_update = sut.tag_update_results[0]
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap();
+        assert_eq!(tag_update.old_version_req, "^1.0.0");
+        assert_eq!(tag_update.new_version_req, "^2.0.0");
+
+        // the requirement is rewritten even if the pin stays
+        let sut = update_test_file_with(
+            "./test/update/default.env",
+            bump_range(RangeBump::To("~1.0.0".to_owned())),
+        );
+        assert_eq!(
+            sut.updated,
+            "# [demver(~1.0.0)|file(versions.ini)|testapp] 1.0.0 @ 2021-01-02T03-04-05\n\
+             TEST_VERSION=sha256:1\n"
+        );
+    }
+
+    #[test]
+    fn update_group_moves_together() {
+        let scanner = TagScanner::new(vec![
+            "./test/consistent/app.env".to_owned(),
+            "./test/consistent/wide.env".to_owned(),
+        ]);
+        let file_infos = scanner.do_scan();
+        let groups = crate::tag_group::group_by_source(&file_infos);
+        let now = syntax::parse_timestamp(TEST_TIMESTAMP).unwrap();
+        let sut =
+            TagUpdater::new(ResolvePolicy::new(now), UpdateOptions::default()).with_groups(&groups);
+
+        // on its own, >=1.0.0 would move to 2.0.0
+        let file_update = sut.update_file(&file_infos[1]).unwrap();
+        assert!(file_update.updated.contains("TEST_VERSION=sha256:15\n"));
+    }
+
+    #[test]
+    fn update_group_all_or_none() {
+        let scanner = TagScanner::new(vec![
+            "./test/groups/api.env".to_owned(),
+            "./test/groups/worker.env".to_owned(),
+            "./test/groups/broken.env".to_owned(),
+        ]);
+        let file_infos = scanner.do_scan();
+        let now = syntax::parse_timestamp(TEST_TIMESTAMP).unwrap();
+
+        // the worker has no 2.0.0 yet
+        let groups = crate::tag_group::group_by_attribute(&file_infos[..2]);
+        let sut =
+            TagUpdater::new(ResolvePolicy::new(now), UpdateOptions::default()).with_groups(&groups);
+        let api_update = sut.update_file(&file_infos[0]).unwrap();
+        assert!(api_update.updated.contains("API_VERSION=sha256:api15\n"));
+
+        // the target of one member is broken, so no member is updated
+        let groups = crate::tag_group::group_by_attribute(&file_infos);
+        let sut =
+            TagUpdater::new(ResolvePolicy::new(now), UpdateOptions::default()).with_groups(&groups);
+        let api_update = sut.update_file(&file_infos[0]).unwrap();
+        assert!(!api_update.has_changes());
+        assert!(api_update.tag_update_results[0]
+            .as_ref()
+            .unwrap_err()
+            .starts_with("group app: ./test/groups/broken.env: "));
+    }
+
+    #[test]
+    fn find_hash_whole_token() {
+        assert_eq!(find_hash("X=sha256:15", "sha256:1"), None);
+        assert_eq!(find_hash("X=c61e1", "1"), None);
+        assert_eq!(find_hash("X=1", "1"), Some(2));
+        assert_eq!(find_hash("FROM app@sha256:1 AS base", "sha256:1"), Some(9));
+    }
+
+    #[test]
+    fn find_target_line_missing() {
+        assert!(find_target_line("# tag", 5).is_err());
+    }
+}
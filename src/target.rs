@@ -0,0 +1,101 @@
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetKind {
+    /// `KEY=VALUE`, also with `ENV`, `ARG` or `export` in front.
+    Assignment,
+    /// An image reference, e.g. `FROM image:tag` or `image: image:tag`.
+    Image,
+}
+
+/// The value in a target line that is replaced by the pinned hash.
+#[derive(Debug, PartialEq)]
+pub struct TargetValue {
+    pub start: usize,
+    pub end: usize,
+    pub kind: TargetKind,
+}
+
+impl TargetValue {
+    /// Finds the value in a line, `None` if the line looks like no target.
+    pub fn find(line: &str) -> Option<Self> {
+        lazy_static! {
+            static ref RES: Vec<(Regex, TargetKind)> = vec![
+                (
+                    Regex::new(r"^\s*(?i:FROM)\s+(?:--platform=\S+\s+)?([^\s]+)").unwrap(),
+                    TargetKind::Image
+                ),
+                (
+                    Regex::new(r#"^\s*(?:-\s+)?image:\s*["']?([^\s"']+)"#).unwrap(),
+                    TargetKind::Image
+                ),
+                (
+                    Regex::new(
+                        r#"^\s*(?:(?:ENV|ARG|export)\s+)?[A-Za-z_][A-Za-z0-9_]*=["']?([^\s"']*)"#
+                    )
+                    .unwrap(),
+                    TargetKind::Assignment
+                ),
+            ];
+        }
+
+        RES.iter().find_map(|(re, kind)| {
+            re.captures(line).map(|captures| {
+                let value = captures.get(1).unwrap();
+                TargetValue {
+                    start: value.start(),
+                    end: value.end(),
+                    kind: *kind,
+                }
+            })
+        })
+    }
+
+    /// Returns what replaces the value to pin the hash: images are referenced
+    /// by their digest, assignments get the bare hash.
+    pub fn pin(&self, line: &str, hash: &str) -> String {
+        let value = &line[self.start..self.end];
+        let pinned = match self.kind {
+            TargetKind::Image => format!("{}@{}", get_repository(value), hash),
+            TargetKind::Assignment => hash.to_owned(),
+        };
+        format!("{}{}{}", &line[..self.start], pinned, &line[self.end..])
+    }
+}
+
+/// Strips tag and digest from an image reference, keeping a registry port.
+pub fn get_repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap();
+    let name_start = image.rfind('/').map_or(0, |idx| idx + 1);
+    match image[name_start..].find(':') {
+        Some(idx) => &image[..name_start + idx],
+        None => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(line: &str) -> Option<String> {
+        TargetValue::find(line).map(|target| target.pin(line, "sha256:15"))
+    }
+
+    #[test]
+    fn pin_targets() {
+        assert_eq!(
+            pin("FROM testapp:1.0 AS build").unwrap(),
+            "FROM testapp@sha256:15 AS build"
+        );
+        assert_eq!(
+            pin("    image: \"registry:5000/testapp:1.0\"").unwrap(),
+            "    image: \"registry:5000/testapp@sha256:15\""
+        );
+        assert_eq!(pin("TEST_VERSION=").unwrap(), "TEST_VERSION=sha256:15");
+        assert_eq!(
+            pin("ENV TEST_VERSION=latest").unwrap(),
+            "ENV TEST_VERSION=sha256:15"
+        );
+        assert_eq!(pin("RUN make"), None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+/// Bytes that are not valid UTF-8 are mapped to the last 256 code points of
+/// the supplementary private use area, so they survive a read/write cycle.
+const ESCAPE_BASE: u32 = 0x10_FF00;
+
+#[derive(Debug, Clone)]
+pub struct TextFile {
+    pub content: String,
+    has_bom: bool,
+    has_escaped_bytes: bool,
+}
+
+impl TextFile {
+    pub fn read(filename: &str) -> Result<Self, String> {
+        let bytes = fs::read(filename).map_err(|e| format!("failed to read file: {}", e))?;
+        Self::decode(&bytes)
+    }
+
+    /// Decodes file content losslessly: a BOM is stripped and remembered,
+    /// invalid UTF-8 bytes are escaped instead of rejected.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let (has_bom, bytes) = match bytes.strip_prefix(UTF8_BOM) {
+            Some(rest) => (true, rest),
+            None => (false, bytes),
+        };
+
+        if let Ok(content) = std::str::from_utf8(bytes) {
+            return Ok(Self {
+                content: content.to_owned(),
+                has_bom,
+                has_escaped_bytes: false,
+            });
+        }
+
+        let mut content = String::with_capacity(bytes.len());
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let (valid, invalid_len) = match std::str::from_utf8(rest) {
+                Ok(valid) => (valid, 0),
+                Err(e) => {
+                    let valid = std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap();
+                    let invalid_len = e.error_len().unwrap_or(rest.len() - e.valid_up_to());
+                    (valid, invalid_len)
+                }
+            };
+            if valid.chars().any(is_escaped_byte) {
+                return Err("file is neither valid UTF-8 nor losslessly decodable".to_owned());
+            }
+            content.push_str(valid);
+
+            let invalid = &rest[valid.len()..valid.len() + invalid_len];
+            for byte in invalid {
+                content.push(std::char::from_u32(ESCAPE_BASE + u32::from(*byte)).unwrap());
+            }
+            rest = &rest[valid.len() + invalid_len..];
+        }
+
+        Ok(Self {
+            content,
+            has_bom,
+            has_escaped_bytes: true,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.content.len() + UTF8_BOM.len());
+        if self.has_bom {
+            bytes.extend_from_slice(UTF8_BOM);
+        }
+        if !self.has_escaped_bytes {
+            bytes.extend_from_slice(self.content.as_bytes());
+            return bytes;
+        }
+
+        let mut buf = [0; 4];
+        for c in self.content.chars() {
+            if is_escaped_byte(c) {
+                bytes.push((c as u32 - ESCAPE_BASE) as u8);
+            } else {
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// The line ending to use for new lines, `\r\n` if the file uses it.
+    pub fn get_line_ending(&self) -> &'static str {
+        match self.content.find('\n') {
+            Some(idx) if self.content[..idx].ends_with('\r') => "\r\n",
+            _ => "\n",
+        }
+    }
+
+    /// Returns a copy of this file with different content but the same
+    /// encoding properties.
+    pub fn with_content(&self, content: String) -> Self {
+        Self {
+            content,
+            has_bom: self.has_bom,
+            has_escaped_bytes: self.has_escaped_bytes,
+        }
+    }
+
+    /// Writes to a temporary file next to the destination and renames it, so
+    /// the destination is never left half written. Permissions of an existing
+    /// destination are kept and symlinks are followed.
+    pub fn write(&self, filename: &str) -> Result<(), String> {
+        let path = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let permissions = fs::metadata(&path).map(|m| m.permissions()).ok();
+        let write_err = |e: std::io::Error| format!("failed to write file {}: {}", filename, e);
+
+        let mut tmp_file = tempfile::NamedTempFile::new_in(dir).map_err(write_err)?;
+        tmp_file.write_all(&self.encode()).map_err(write_err)?;
+        if let Some(permissions) = permissions {
+            tmp_file
+                .as_file()
+                .set_permissions(permissions)
+                .map_err(write_err)?;
+        }
+        tmp_file.as_file().sync_all().map_err(write_err)?;
+        tmp_file.persist(&path).map_err(|e| write_err(e.error))?;
+
+        Ok(())
+    }
+}
+
+fn is_escaped_byte(c: char) -> bool {
+    (ESCAPE_BASE..=ESCAPE_BASE + 0xFF).contains(&(c as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_plain() {
+        let bytes = b"line 1\r\nline 2";
+        let sut = TextFile::decode(bytes).unwrap();
+
+        assert_eq!(sut.content, "line 1\r\nline 2");
+        assert_eq!(sut.encode(), bytes);
+    }
+
+    #[test]
+    fn roundtrip_bom() {
+        let bytes = b"\xEF\xBB\xBF# [demver]\n";
+        let sut = TextFile::decode(bytes).unwrap();
+
+        assert_eq!(sut.content, "# [demver]\n");
+        assert_eq!(sut.encode(), bytes);
+    }
+
+    #[test]
+    fn roundtrip_invalid_utf8() {
+        let bytes = b"caf\xE9 # [demver]\n\xFF\xFE\n";
+        let sut = TextFile::decode(bytes).unwrap();
+
+        assert!(sut.content.contains(" # [demver]\n"));
+        assert_eq!(sut.encode(), bytes);
+
+        let changed = sut.with_content(sut.content.replace("demver", "DEMVER"));
+        assert_eq!(changed.encode(), b"caf\xE9 # [DEMVER]\n\xFF\xFE\n");
+    }
+
+    #[test]
+    fn line_ending() {
+        let line_ending = |content: &[u8]| TextFile::decode(content).unwrap().get_line_ending();
+
+        assert_eq!(line_ending(b"a\r\nb\n"), "\r\n");
+        assert_eq!(line_ending(b"a\nb\r\n"), "\n");
+        assert_eq!(line_ending(b"a"), "\n");
+    }
+
+    #[test]
+    fn decode_escape_collision() {
+        let mut bytes = "\u{10FF41}".as_bytes().to_vec();
+        bytes.push(0xFF);
+
+        assert!(TextFile::decode(&bytes).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("script.sh");
+        fs::write(&filename, "old").unwrap();
+        fs::set_permissions(&filename, fs::Permissions::from_mode(0o751)).unwrap();
+        let filename = filename.to_str().unwrap();
+
+        let sut = TextFile::read(filename)
+            .unwrap()
+            .with_content("new".to_owned());
+        sut.write(filename).unwrap();
+
+        let metadata = fs::metadata(filename).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o751);
+        assert_eq!(fs::read_to_string(filename).unwrap(), "new");
+    }
+}
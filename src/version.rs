@@ -1,15 +1,18 @@
+use crate::scheme;
+use chrono::{DateTime, Utc};
 use std::cmp::Ordering;
 
 #[derive(Debug, Clone, Eq)]
 pub struct FixedVersion {
     pub raw_version: String,
-    pub semver: semver::Version,
+    pub version: scheme::Version,
     pub hash: String,
+    pub released: Option<DateTime<Utc>>,
 }
 
 impl FixedVersion {
-    pub fn get_semver(&self) -> &semver::Version {
-        &self.semver
+    pub fn get_version(&self) -> &scheme::Version {
+        &self.version
     }
 
     pub fn get_hash(&self) -> &String {
@@ -23,14 +26,52 @@ impl PartialOrd for FixedVersion {
     }
 }
 
+/// Versions that are equal in their scheme, e.g. `v1.2` and `1.2.0`, are
+/// ordered by their raw version to keep the choice between them deterministic.
 impl Ord for FixedVersion {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.semver.cmp(&other.semver)
+        self.version
+            .cmp(&other.version)
+            .then_with(|| self.raw_version.cmp(&other.raw_version))
     }
 }
 
 impl PartialEq for FixedVersion {
     fn eq(&self, other: &Self) -> bool {
-        self.semver == other.semver
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(raw_version: &str) -> FixedVersion {
+        FixedVersion {
+            raw_version: raw_version.to_owned(),
+            version: scheme::VersionScheme::Semver
+                .parse_version(raw_version)
+                .unwrap(),
+            hash: raw_version.to_owned(),
+            released: None,
+        }
+    }
+
+    #[test]
+    fn order_build_metadata() {
+        let mut versions = [
+            version("1.0.0+build.10"),
+            version("1.0.0+build.2"),
+            version("1.0.0"),
+            version("1.0.0+build.9"),
+        ];
+        versions.sort();
+
+        let sorted: Vec<&str> = versions.iter().map(|v| v.raw_version.as_str()).collect();
+        assert_eq!(
+            sorted,
+            vec!["1.0.0", "1.0.0+build.2", "1.0.0+build.9", "1.0.0+build.10"]
+        );
+        assert_ne!(version("1.0.0+a"), version("1.0.0+b"));
     }
 }
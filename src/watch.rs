@@ -0,0 +1,210 @@
+use crate::tag_checker::TagChecker;
+use crate::tag_scanner::{FileInfo, TagScanner};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+/// Changes within this time are reported together.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(300);
+
+/// Which tags of a scanned file have to be checked again.
+#[derive(Debug, PartialEq)]
+pub enum Affected {
+    /// the file itself changed and was scanned again
+    All,
+    /// indices of the tags whose sources changed
+    Tags(Vec<usize>),
+}
+
+/// Files may be removed or not created yet, so they can't always be
+/// canonicalized.
+fn get_absolute_path(filename: &str) -> PathBuf {
+    std::fs::canonicalize(filename).unwrap_or_else(|_| {
+        std::env::current_dir()
+            .map(|dir| dir.join(filename))
+            .unwrap_or_else(|_| PathBuf::from(filename))
+    })
+}
+
+fn get_tag_sources(file_info: &FileInfo) -> Vec<Vec<PathBuf>> {
+    match &file_info.version_result {
+        Ok(file_version) => file_version
+            .tag_version_results
+            .iter()
+            .map(|result| match result {
+                Ok(tag_version) => TagChecker::get_source_files(&tag_version.tag)
+                    .iter()
+                    .map(|filename| get_absolute_path(filename))
+                    .collect(),
+                Err(_) => vec![],
+            })
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// The files a check depends on: the scanned files and the sources of
+/// their tags.
+pub fn get_dependencies(file_infos: &[FileInfo]) -> BTreeSet<PathBuf> {
+    let mut dependencies = BTreeSet::new();
+    for file_info in file_infos {
+        dependencies.insert(get_absolute_path(&file_info.filename));
+        dependencies.extend(get_tag_sources(file_info).into_iter().flatten());
+    }
+    dependencies
+}
+
+/// Scans changed files again and returns, per index of the scanned files,
+/// the tags affected by the changes.
+pub fn apply_changes(
+    file_infos: &mut [FileInfo],
+    changed: &HashSet<PathBuf>,
+) -> Vec<(usize, Affected)> {
+    let mut affected = vec![];
+    for (idx, file_info) in file_infos.iter_mut().enumerate() {
+        if changed.contains(&get_absolute_path(&file_info.filename)) {
+            let mut rescanned = TagScanner::new(vec![file_info.filename.clone()]).do_scan();
+            *file_info = rescanned.remove(0);
+            affected.push((idx, Affected::All));
+            continue;
+        }
+
+        let tag_indices: Vec<usize> = get_tag_sources(file_info)
+            .iter()
+            .enumerate()
+            .filter(|(_, sources)| sources.iter().any(|source| changed.contains(source)))
+            .map(|(tag_idx, _)| tag_idx)
+            .collect();
+        if !tag_indices.is_empty() {
+            affected.push((idx, Affected::Tags(tag_indices)));
+        }
+    }
+    affected
+}
+
+/// Reports changes of a set of files.
+pub struct FileWatcher {
+    watcher: RecommendedWatcher,
+    receiver: Receiver<DebouncedEvent>,
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeSet<PathBuf>,
+}
+
+impl FileWatcher {
+    pub fn new() -> Result<Self, String> {
+        let (sender, receiver) = channel();
+        let watcher = notify::watcher(sender, DEBOUNCE_DELAY)
+            .map_err(|e| format!("failed to watch files: {}", e))?;
+        Ok(Self {
+            watcher,
+            receiver,
+            dirs: BTreeSet::new(),
+            files: BTreeSet::new(),
+        })
+    }
+
+    /// Watches exactly these files. Their directories are watched instead of
+    /// the files, so that files replaced by editors on save are still noticed.
+    pub fn watch(&mut self, files: BTreeSet<PathBuf>) -> Result<(), String> {
+        let dirs: BTreeSet<PathBuf> = files
+            .iter()
+            .filter_map(|file| file.parent().map(Path::to_path_buf))
+            .filter(|dir| dir.is_dir())
+            .collect();
+
+        for dir in self.dirs.difference(&dirs) {
+            // fails if the directory was removed, which is fine
+            let _ = self.watcher.unwatch(dir);
+        }
+        for dir in dirs.difference(&self.dirs) {
+            self.watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(|e| format!("failed to watch {}: {}", dir.display(), e))?;
+        }
+
+        self.dirs = dirs;
+        self.files = files;
+        Ok(())
+    }
+
+    /// Blocks until watched files change and returns all of them.
+    pub fn wait(&self) -> Result<HashSet<PathBuf>, String> {
+        loop {
+            let mut events = vec![self
+                .receiver
+                .recv()
+                .map_err(|_| "the file watcher stopped".to_owned())?];
+            events.extend(self.receiver.try_iter());
+
+            let changed: HashSet<PathBuf> = events
+                .into_iter()
+                .flat_map(|event| self.get_changed_files(event))
+                .collect();
+            if !changed.is_empty() {
+                return Ok(changed);
+            }
+        }
+    }
+
+    fn get_changed_files(&self, event: DebouncedEvent) -> Vec<PathBuf> {
+        let paths = match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Remove(path) => vec![path],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            // events were lost, anything may have changed
+            DebouncedEvent::Rescan => return self.files.iter().cloned().collect(),
+            _ => vec![],
+        };
+        paths
+            .into_iter()
+            .filter(|path| self.files.contains(path))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dependencies() {
+        let file_infos = TagScanner::new(vec!["./test/groups/api.env".to_owned()]).do_scan();
+
+        let dependencies = get_dependencies(&file_infos);
+
+        assert_eq!(
+            dependencies.into_iter().collect::<Vec<_>>(),
+            [
+                get_absolute_path("./test/groups/api.env"),
+                get_absolute_path("./test/groups/versions.ini")
+            ]
+        );
+    }
+
+    #[test]
+    fn changes() {
+        let mut file_infos = TagScanner::new(vec![
+            "./test/groups/api.env".to_owned(),
+            "./test/simple/default.env".to_owned(),
+        ])
+        .do_scan();
+
+        let changed = HashSet::from([get_absolute_path("./test/groups/versions.ini")]);
+        assert_eq!(
+            apply_changes(&mut file_infos, &changed),
+            [(0, Affected::Tags(vec![0]))]
+        );
+
+        let changed = HashSet::from([
+            get_absolute_path("./test/simple/default.env"),
+            get_absolute_path("./test/other.ini"),
+        ]);
+        assert_eq!(
+            apply_changes(&mut file_infos, &changed),
+            [(1, Affected::All)]
+        );
+    }
+}